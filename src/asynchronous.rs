@@ -0,0 +1,619 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Async I2C connections for tokio
+//!
+//! Available with the `tokio` feature. Bus ioctls block, so they run on
+//! tokio's blocking thread pool, while delays and retry backoff are awaited
+//! with the runtime's timer. [`AsyncConnection`] has the same transactions
+//! as [`Connection`](crate::Connection), SMBus ones included, and traces
+//! them the same way with the `log` feature enabled.
+//!
+//! ```
+//! use i2c_rs::asynchronous::{AsyncConnection, Blocking};
+//! use i2c_rs::{Command, MockStream};
+//! use std::time::Duration;
+//!
+//! let mock = MockStream::new();
+//! mock.expect_write(vec![0x10, 0x01], Ok(()));
+//! mock.expect_read(vec![], 2, Ok(vec![0x12, 0x34]));
+//!
+//! let connection = AsyncConnection::new(Box::new(Blocking::new(mock.clone())));
+//! let runtime = tokio::runtime::Builder::new_current_thread()
+//!     .enable_time()
+//!     .build()
+//!     .unwrap();
+//! let command = Command { cmd: 0x10, data: vec![0x01] };
+//! let data = runtime
+//!     .block_on(connection.transfer(command, 2, Duration::from_millis(5)))
+//!     .unwrap();
+//! assert_eq!(data, vec![0x12, 0x34]);
+//! ```
+//!
+//! # Cancellation
+//!
+//! Dropping a future, e.g. through `tokio::time::timeout` or `select!`,
+//! cancels the transaction at its next await point. An ioctl that has
+//! already been handed to the blocking pool can't be interrupted and still
+//! runs to completion, but its result is discarded, and a transfer cancelled
+//! during its delay never issues its read.
+
+use crate::smbus::Emulated;
+use crate::{
+    block_bytes, Address, Command, I2CStream, I2cError, I2cResult, RegisterAddress, Response,
+    Retried, RetryPolicy, SmbusStream, Transaction,
+};
+use hal_stream::Stream;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::panic;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::{self, JoinError};
+
+/// A boxed future, as returned by [`AsyncStream`] methods
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A blocking SMBus transaction, returning the bytes it read, as passed to
+/// [`AsyncStream::smbus`]
+pub type SmbusCall = Box<dyn FnOnce(&dyn SmbusStream) -> Result<Vec<u8>> + Send>;
+
+/// Async counterpart of `hal_stream::Stream`
+pub trait AsyncStream: Send + Sync {
+    /// Writes `command`
+    fn write(&self, command: Vec<u8>) -> BoxFuture<'_, Result<()>>;
+
+    /// Writes `command` and reads `rx_len` bytes back in a single combined
+    /// transaction. An empty `command` results in a plain read.
+    fn read(&self, command: Vec<u8>, rx_len: usize) -> BoxFuture<'_, Result<Vec<u8>>>;
+
+    /// Writes `command`, waits for `delay` and reads `rx_len` bytes
+    ///
+    /// By default this is a write followed by a plain read, with the delay
+    /// awaited on the runtime's timer in between. The pair is then not
+    /// atomic: other users of the bus may get a transaction in between.
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        delay: Option<Duration>,
+    ) -> BoxFuture<'_, Result<Vec<u8>>> {
+        Box::pin(async move {
+            self.write(command).await?;
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            self.read(Vec::new(), rx_len).await
+        })
+    }
+
+    /// Runs `f` against a blocking [`SmbusStream`] for this stream
+    ///
+    /// By default this fails with an `Unsupported` error.
+    fn smbus(&self, _f: SmbusCall) -> BoxFuture<'_, Result<Vec<u8>>> {
+        Box::pin(async {
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "stream does not support SMBus transactions",
+            ))
+        })
+    }
+}
+
+/// An [`AsyncStream`] which runs a blocking `Stream` on tokio's blocking
+/// thread pool
+///
+/// A transfer is split into a write and a read so that its delay doesn't
+/// tie up a pool thread. Other users of the bus may get a transaction in
+/// between the two.
+///
+/// SMBus transactions are emulated on top of the stream's raw transfers,
+/// unless the stream was wrapped with [`Blocking::with_smbus_stream`].
+pub struct Blocking<S> {
+    stream: Arc<S>,
+    smbus: Arc<dyn SmbusStream + Send + Sync>,
+}
+
+impl<S> Blocking<S>
+where
+    S: Stream<StreamError = Error> + Send + Sync + 'static,
+{
+    /// Wraps a blocking stream
+    ///
+    /// # Arguments
+    ///
+    /// `stream` - Stream to run on the blocking pool
+    pub fn new(stream: S) -> Self {
+        let stream = Arc::new(stream);
+        Self {
+            smbus: Arc::new(Emulated(stream.clone())),
+            stream,
+        }
+    }
+
+    /// Runs `f` against the stream on the blocking pool
+    fn offload<T, F>(&self, f: F) -> BoxFuture<'static, Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T> + Send + 'static,
+    {
+        let stream = self.stream.clone();
+        Box::pin(async move { joined(task::spawn_blocking(move || f(&stream)).await) })
+    }
+}
+
+impl<S> Blocking<S>
+where
+    S: SmbusStream + Send + Sync + 'static,
+{
+    /// Wraps a blocking stream with its own SMBus support, such as an
+    /// [`I2CStream`]
+    ///
+    /// # Arguments
+    ///
+    /// `stream` - Stream to run on the blocking pool
+    pub fn with_smbus_stream(stream: S) -> Self {
+        let stream = Arc::new(stream);
+        Self {
+            smbus: stream.clone(),
+            stream,
+        }
+    }
+}
+
+impl<S> AsyncStream for Blocking<S>
+where
+    S: Stream<StreamError = Error> + Send + Sync + 'static,
+{
+    fn write(&self, command: Vec<u8>) -> BoxFuture<'_, Result<()>> {
+        self.offload(move |s| s.write(command))
+    }
+
+    fn read(&self, command: Vec<u8>, rx_len: usize) -> BoxFuture<'_, Result<Vec<u8>>> {
+        self.offload(move |s| s.read(&mut command.clone(), rx_len))
+    }
+
+    fn smbus(&self, f: SmbusCall) -> BoxFuture<'_, Result<Vec<u8>>> {
+        let smbus = self.smbus.clone();
+        Box::pin(async move { joined(task::spawn_blocking(move || f(&*smbus)).await) })
+    }
+}
+
+/// Result of a blocking task, with its panic, if any, passed on to the
+/// awaiting task
+pub(crate) fn joined<T>(result: std::result::Result<Result<T>, JoinError>) -> Result<T> {
+    match result {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(Error::other(e)),
+    }
+}
+
+/// Async counterpart of [`Connection`](crate::Connection)
+///
+/// Failed transactions are retried according to the connection's
+/// [`RetryPolicy`], with the backoff awaited on the runtime's timer.
+///
+/// With the `log` feature enabled, every transaction attempt is logged
+/// like those of a [`Connection`](crate::Connection), at `Debug` level
+/// unless `AsyncConnection::with_trace_level` says otherwise.
+pub struct AsyncConnection {
+    stream: Box<dyn AsyncStream>,
+    retry: RetryPolicy,
+    path: Option<String>,
+    address: Option<Address>,
+    #[cfg(feature = "log")]
+    trace_level: Option<log::Level>,
+}
+
+impl AsyncConnection {
+    /// Async I2C connection constructor
+    ///
+    /// # Arguments
+    ///
+    /// `stream` - Stream to communicate through
+    pub fn new(stream: Box<dyn AsyncStream>) -> Self {
+        Self {
+            stream,
+            retry: RetryPolicy::default(),
+            path: None,
+            address: None,
+            #[cfg(feature = "log")]
+            trace_level: Some(log::Level::Debug),
+        }
+    }
+
+    /// Convenience constructor for creating an AsyncConnection with an
+    /// I2CStream run on the blocking pool
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path to I2C device
+    /// `slave` - I2C slave address, either a plain 7-bit address or an
+    ///           [`Address`]
+    pub fn from_path(path: &str, slave: impl Into<Address>) -> Self {
+        let slave = slave.into();
+        let stream = Blocking::with_smbus_stream(I2CStream::new(path, slave));
+        let mut connection = Self::new(Box::new(stream));
+        connection.path = Some(path.to_string());
+        connection.address = Some(slave);
+        connection
+    }
+
+    /// Sets the level at which this connection's transactions are logged
+    ///
+    /// # Arguments
+    ///
+    /// `level` - Log level, or `None` to not log transactions at all
+    #[cfg(feature = "log")]
+    pub fn with_trace_level(mut self, level: Option<log::Level>) -> Self {
+        self.trace_level = level;
+        self
+    }
+
+    /// Sets the retry policy for all transactions on this connection
+    ///
+    /// # Arguments
+    ///
+    /// `policy` - Retry policy
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Retry policy for transactions on this connection
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Writes an I2C command
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to write
+    pub async fn write<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+    ) -> I2cResult<()> {
+        self.write_with(command, &self.retry).await.map(|r| r.value)
+    }
+
+    /// Writes an I2C command, retrying according to `policy`
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to write
+    /// `policy` - Retry policy to use instead of the connection's
+    pub async fn write_with<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        policy: &RetryPolicy,
+    ) -> I2cResult<Retried<()>> {
+        let buf = command.to_bytes();
+        let transaction = Transaction::new("write", command.command_byte(), &buf);
+        self.call(policy, &transaction, || self.stream.write(buf.clone()))
+            .await
+    }
+
+    /// Reads command result
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to read result from
+    /// `rx_len`  - Amount of data to read
+    pub async fn read<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+    ) -> I2cResult<Vec<u8>> {
+        self.read_with(command, rx_len, &self.retry)
+            .await
+            .map(|r| r.value)
+    }
+
+    /// Reads command result, retrying according to `policy`
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to read result from
+    /// `rx_len`  - Amount of data to read
+    /// `policy` - Retry policy to use instead of the connection's
+    pub async fn read_with<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+        policy: &RetryPolicy,
+    ) -> I2cResult<Retried<Vec<u8>>> {
+        let buf = command.to_bytes();
+        let transaction = Transaction::new("read", command.command_byte(), &buf);
+        self.call(policy, &transaction, || {
+            self.stream.read(buf.clone(), rx_len)
+        })
+        .await
+    }
+
+    /// Writes I2C command and reads result
+    ///
+    /// The write and the read are not atomic. A [`Blocking`] stream runs
+    /// them as separate tasks on the blocking pool, so an [`I2CStream`] bus
+    /// lock is released in between and other processes may use the bus
+    /// during the delay. Use [`AsyncConnection::read`] for a single combined
+    /// transaction if the device needs no delay.
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to write and read from
+    /// `rx_len`  - Amount of data to read
+    /// `delay` - Delay between writing and reading
+    pub async fn transfer<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+        delay: Duration,
+    ) -> I2cResult<Vec<u8>> {
+        self.transfer_with(command, rx_len, delay, &self.retry)
+            .await
+            .map(|r| r.value)
+    }
+
+    /// Writes I2C command and reads result, retrying according to `policy`
+    ///
+    /// Like [`AsyncConnection::transfer`], the write and the read are not
+    /// atomic.
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to write and read from
+    /// `rx_len`  - Amount of data to read
+    /// `delay` - Delay between writing and reading
+    /// `policy` - Retry policy to use instead of the connection's
+    pub async fn transfer_with<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+        delay: Duration,
+        policy: &RetryPolicy,
+    ) -> I2cResult<Retried<Vec<u8>>> {
+        let buf = command.to_bytes();
+        let transaction = Transaction::new("transfer", command.command_byte(), &buf);
+        self.call(policy, &transaction, || {
+            self.stream.transfer(buf.clone(), rx_len, Some(delay))
+        })
+        .await
+    }
+
+    /// Performs an SMBus Quick Command
+    ///
+    /// # Arguments
+    ///
+    /// `read` - Value of the R/W bit
+    pub async fn quick_command(&self, read: bool) -> I2cResult<()> {
+        self.smbus(Transaction::new("quick_command", None, &[]), move |s| {
+            s.quick_command(read)
+        })
+        .await
+    }
+
+    /// Performs an SMBus Receive Byte
+    pub async fn receive_byte(&self) -> I2cResult<u8> {
+        self.smbus(Transaction::new("receive_byte", None, &[]), |s| {
+            s.receive_byte()
+        })
+        .await
+    }
+
+    /// Performs an SMBus Send Byte
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Byte to send
+    pub async fn send_byte(&self, value: u8) -> I2cResult<()> {
+        self.smbus(Transaction::new("send_byte", None, &[value]), move |s| {
+            s.send_byte(value)
+        })
+        .await
+    }
+
+    /// Performs an SMBus Read Byte
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    pub async fn read_byte_data(&self, cmd: u8) -> I2cResult<u8> {
+        self.smbus(
+            Transaction::new("read_byte_data", Some(cmd), &[cmd]),
+            move |s| s.read_byte_data(cmd),
+        )
+        .await
+    }
+
+    /// Performs an SMBus Write Byte
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Byte to write
+    pub async fn write_byte_data(&self, cmd: u8, value: u8) -> I2cResult<()> {
+        self.smbus(
+            Transaction::new("write_byte_data", Some(cmd), &[cmd, value]),
+            move |s| s.write_byte_data(cmd, value),
+        )
+        .await
+    }
+
+    /// Performs an SMBus Read Word
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    pub async fn read_word_data(&self, cmd: u8) -> I2cResult<u16> {
+        self.smbus(
+            Transaction::new("read_word_data", Some(cmd), &[cmd]),
+            move |s| s.read_word_data(cmd),
+        )
+        .await
+    }
+
+    /// Performs an SMBus Write Word
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Word to write
+    pub async fn write_word_data(&self, cmd: u8, value: u16) -> I2cResult<()> {
+        let [lo, hi] = value.to_le_bytes();
+        self.smbus(
+            Transaction::new("write_word_data", Some(cmd), &[cmd, lo, hi]),
+            move |s| s.write_word_data(cmd, value),
+        )
+        .await
+    }
+
+    /// Performs an SMBus Process Call
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Word to write
+    pub async fn process_call(&self, cmd: u8, value: u16) -> I2cResult<u16> {
+        let [lo, hi] = value.to_le_bytes();
+        self.smbus(
+            Transaction::new("process_call", Some(cmd), &[cmd, lo, hi]),
+            move |s| s.process_call(cmd, value),
+        )
+        .await
+    }
+
+    /// Performs an SMBus Block Read
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    pub async fn read_block_data(&self, cmd: u8) -> I2cResult<Vec<u8>> {
+        self.smbus(
+            Transaction::new("read_block_data", Some(cmd), &[cmd]),
+            move |s| s.read_block_data(cmd),
+        )
+        .await
+    }
+
+    /// Performs an SMBus Block Write
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `data` - Up to 32 bytes to write
+    pub async fn write_block_data(&self, cmd: u8, data: &[u8]) -> I2cResult<()> {
+        let tx = block_bytes(cmd, data);
+        let data = data.to_vec();
+        self.smbus(
+            Transaction::new("write_block_data", Some(cmd), &tx),
+            move |s| s.write_block_data(cmd, &data),
+        )
+        .await
+    }
+
+    /// Performs an SMBus Block Write-Block Read Process Call
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `data` - Up to 32 bytes to write
+    pub async fn block_process_call(&self, cmd: u8, data: &[u8]) -> I2cResult<Vec<u8>> {
+        let tx = block_bytes(cmd, data);
+        let data = data.to_vec();
+        self.smbus(
+            Transaction::new("block_process_call", Some(cmd), &tx),
+            move |s| s.block_process_call(cmd, &data),
+        )
+        .await
+    }
+
+    /// Runs an SMBus transaction on the blocking pool under the
+    /// connection's retry policy
+    async fn smbus<T, F>(&self, transaction: Transaction<'_>, f: F) -> I2cResult<T>
+    where
+        T: Response,
+        F: Fn(&dyn SmbusStream) -> Result<T> + Clone + Send + 'static,
+    {
+        self.call(&self.retry, &transaction, || {
+            let f = f.clone();
+            self.stream
+                .smbus(Box::new(move |s| f(s).map(|v| v.bytes())))
+        })
+        .await
+        .map(|r| T::from_bytes(r.value))
+    }
+
+    /// Runs a transaction on the stream under `policy`, filling in the
+    /// connection's path and address and the command byte in errors unless
+    /// the stream already did, and traces every attempt
+    async fn call<'a, T, F>(
+        &'a self,
+        policy: &RetryPolicy,
+        transaction: &Transaction<'_>,
+        mut f: F,
+    ) -> I2cResult<Retried<T>>
+    where
+        T: Response,
+        F: FnMut() -> BoxFuture<'a, Result<T>>,
+    {
+        policy
+            .run_async(|| {
+                let attempt = f();
+                async move {
+                    let start = Instant::now();
+                    let result = attempt.await.map_err(|e| {
+                        I2cError::from(e).with_context(
+                            self.path.as_deref(),
+                            self.address,
+                            transaction.cmd,
+                        )
+                    });
+                    self.trace(transaction, &result, start.elapsed());
+                    result
+                }
+            })
+            .await
+    }
+
+    #[cfg(feature = "log")]
+    fn trace<T: Response>(
+        &self,
+        transaction: &Transaction<'_>,
+        result: &I2cResult<T>,
+        duration: Duration,
+    ) {
+        crate::log_transaction(
+            self.trace_level,
+            self.path.as_deref(),
+            self.address,
+            transaction,
+            result,
+            duration,
+        );
+    }
+
+    #[cfg(not(feature = "log"))]
+    fn trace<T>(
+        &self,
+        _transaction: &Transaction<'_>,
+        _result: &I2cResult<T>,
+        _duration: Duration,
+    ) {
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sharing an I2C adapter between threads
+//!
+//! ```no_run
+//! use i2c_rs::{Command, SharedBus};
+//!
+//! let bus = SharedBus::open("/dev/i2c-1");
+//! let eps = bus.connection(0x2B);
+//! let adcs = bus.connection(0x57);
+//!
+//! // Nothing else on /dev/i2c-1 can run between these two transactions
+//! bus.with_bus(|_tx| {
+//!     eps.write(Command { cmd: 0x10, data: vec![0x01] })?;
+//!     adcs.write(Command { cmd: 0x20, data: vec![0x02] })
+//! })
+//! .unwrap();
+//! ```
+
+use crate::lock::ReentrantLock;
+use crate::{Address, Connection, I2CStream, SmbusStream};
+use hal_stream::Stream;
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+
+/// Adapters with a live [`SharedBus`], by path
+fn registry() -> &'static Mutex<HashMap<String, Weak<BusInner>>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, Weak<BusInner>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A handle to an I2C adapter shared by several connections and threads
+///
+/// Every transaction on a connection handed out by the bus holds the
+/// adapter's lock, so transactions from different threads never interleave.
+/// [`SharedBus::with_bus`] holds the lock across a whole sequence of
+/// transactions. The lock is per adapter and process-wide: all
+/// `SharedBus`es opened for the same path share it.
+///
+/// The lock is re-entrant, so connections from the bus can be used freely
+/// inside `with_bus` on the thread that called it.
+#[derive(Clone)]
+pub struct SharedBus {
+    inner: Arc<BusInner>,
+}
+
+struct BusInner {
+    path: String,
+    lock: ReentrantLock,
+    streams: Mutex<HashMap<Address, Arc<I2CStream>>>,
+}
+
+impl SharedBus {
+    /// Returns the shared handle for an adapter, creating it if needed
+    ///
+    /// The adapter itself is opened lazily, by the first transaction of
+    /// each slave address.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path to I2C adapter
+    pub fn open(path: &str) -> Self {
+        let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+        registry.retain(|_, bus| bus.strong_count() > 0);
+        if let Some(inner) = registry.get(path).and_then(Weak::upgrade) {
+            return Self { inner };
+        }
+        let inner = Arc::new(BusInner {
+            path: path.to_string(),
+            lock: ReentrantLock::default(),
+            streams: Mutex::new(HashMap::new()),
+        });
+        registry.insert(path.to_string(), Arc::downgrade(&inner));
+        Self { inner }
+    }
+
+    /// Path to the I2C adapter
+    pub fn path(&self) -> &str {
+        &self.inner.path
+    }
+
+    /// Creates a connection to a slave on this bus
+    ///
+    /// Connections to the same address share one device handle.
+    ///
+    /// # Arguments
+    ///
+    /// `slave` - I2C slave address, either a plain 7-bit address or an
+    ///           [`Address`]
+    pub fn connection(&self, slave: impl Into<Address>) -> Connection {
+        let slave = slave.into();
+        let stream = self
+            .inner
+            .streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(slave)
+            .or_insert_with(|| Arc::new(I2CStream::new(&self.inner.path, slave)))
+            .clone();
+        let stream = SharedStream {
+            bus: self.inner.clone(),
+            stream,
+        };
+        Connection::with_smbus_stream(Box::new(stream)).located(&self.inner.path, slave)
+    }
+
+    /// Runs `f` with the bus locked, so that transactions made by `f`
+    /// through connections from this bus can't be interleaved with
+    /// transactions from other threads
+    pub fn with_bus<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&BusTransaction<'_>) -> T,
+    {
+        let _guard = self.inner.lock.acquire();
+        f(&BusTransaction { bus: self })
+    }
+}
+
+/// Scope of [`SharedBus::with_bus`], during which the bus stays locked
+pub struct BusTransaction<'a> {
+    bus: &'a SharedBus,
+}
+
+impl BusTransaction<'_> {
+    /// Creates a connection to a slave on the locked bus
+    ///
+    /// # Arguments
+    ///
+    /// `slave` - I2C slave address, either a plain 7-bit address or an
+    ///           [`Address`]
+    pub fn connection(&self, slave: impl Into<Address>) -> Connection {
+        self.bus.connection(slave)
+    }
+
+    /// Path to the I2C adapter
+    pub fn path(&self) -> &str {
+        self.bus.path()
+    }
+}
+
+/// An `I2CStream` whose transactions hold its bus's lock
+struct SharedStream {
+    bus: Arc<BusInner>,
+    stream: Arc<I2CStream>,
+}
+
+impl SharedStream {
+    fn locked<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&I2CStream) -> Result<T>,
+    {
+        let _guard = self.bus.lock.acquire();
+        f(&self.stream)
+    }
+}
+
+impl Stream for SharedStream {
+    type StreamError = std::io::Error;
+
+    fn write(&self, command: Vec<u8>) -> Result<()> {
+        self.locked(|s| s.write(command))
+    }
+
+    fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
+        self.locked(|s| s.write_bytes(command))
+    }
+
+    fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
+        self.locked(|s| s.read(command, rx_len))
+    }
+
+    fn read_timeout(
+        &self,
+        command: &mut Vec<u8>,
+        rx_len: usize,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.locked(|s| s.read_timeout(command, rx_len, timeout))
+    }
+
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        delay: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.locked(|s| s.transfer(command, rx_len, delay))
+    }
+}
+
+impl SmbusStream for SharedStream {
+    fn quick_command(&self, read: bool) -> Result<()> {
+        self.locked(|s| s.quick_command(read))
+    }
+
+    fn receive_byte(&self) -> Result<u8> {
+        self.locked(|s| s.receive_byte())
+    }
+
+    fn send_byte(&self, value: u8) -> Result<()> {
+        self.locked(|s| s.send_byte(value))
+    }
+
+    fn read_byte_data(&self, cmd: u8) -> Result<u8> {
+        self.locked(|s| s.read_byte_data(cmd))
+    }
+
+    fn write_byte_data(&self, cmd: u8, value: u8) -> Result<()> {
+        self.locked(|s| s.write_byte_data(cmd, value))
+    }
+
+    fn read_word_data(&self, cmd: u8) -> Result<u16> {
+        self.locked(|s| s.read_word_data(cmd))
+    }
+
+    fn write_word_data(&self, cmd: u8, value: u16) -> Result<()> {
+        self.locked(|s| s.write_word_data(cmd, value))
+    }
+
+    fn process_call(&self, cmd: u8, value: u16) -> Result<u16> {
+        self.locked(|s| s.process_call(cmd, value))
+    }
+
+    fn read_block_data(&self, cmd: u8) -> Result<Vec<u8>> {
+        self.locked(|s| s.read_block_data(cmd))
+    }
+
+    fn write_block_data(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        self.locked(|s| s.write_block_data(cmd, data))
+    }
+
+    fn block_process_call(&self, cmd: u8, data: &[u8]) -> Result<Vec<u8>> {
+        self.locked(|s| s.block_process_call(cmd, data))
+    }
+
+    fn exclusive(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        self.locked(|s| s.exclusive(f))
+    }
+}
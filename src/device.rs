@@ -0,0 +1,281 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Register-level device drivers
+//!
+//! [`device!`] turns a list of registers into a driver struct wrapping a
+//! [`Connection`], with one method per register:
+//!
+//! ```
+//! use i2c_rs::{device, Connection, MockStream};
+//!
+//! device! {
+//!     /// TMP102 temperature sensor
+//!     pub struct Tmp102 {
+//!         /// Temperature, in 1/256 °C
+//!         temperature: i16 @ 0x00, ReadOnly,
+//!         /// Configuration
+//!         config: u16 @ 0x01, ReadWrite = 0x60A0,
+//!         /// Low temperature threshold
+//!         t_low: i16 @ 0x02, ReadWrite = 0x4B00,
+//!         /// High temperature threshold
+//!         t_high: i16 @ 0x03, ReadWrite = 0x5000,
+//!     }
+//! }
+//!
+//! let mock = MockStream::new();
+//! mock.expect_read(vec![0x00], 2, Ok(vec![0x19, 0x00]));
+//! mock.expect_write(vec![0x02, 0x4B, 0x00], Ok(()));
+//!
+//! let sensor = Tmp102::new(Connection::new(Box::new(mock.clone())));
+//! assert_eq!(sensor.temperature().read().unwrap(), 0x1900);
+//! sensor.t_low().reset().unwrap();
+//! ```
+//!
+//! [`device!`]: macro@crate::device
+
+use crate::{Connection, Endian, I2cResult, RegisterValue};
+use std::marker::PhantomData;
+
+/// Access mode of a register which can only be read
+pub struct ReadOnly;
+
+/// Access mode of a register which can only be written
+pub struct WriteOnly;
+
+/// Access mode of a register which can be read and written
+pub struct ReadWrite;
+
+/// Access mode of a register
+pub trait Access {
+    /// Whether the register can be written
+    const WRITABLE: bool;
+}
+
+/// Access mode of a register which can be read
+pub trait Readable: Access {}
+
+/// Access mode of a register which can be written
+pub trait Writable: Access {}
+
+impl Access for ReadOnly {
+    const WRITABLE: bool = false;
+}
+
+impl Access for WriteOnly {
+    const WRITABLE: bool = true;
+}
+
+impl Access for ReadWrite {
+    const WRITABLE: bool = true;
+}
+
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// A register of a device, as returned by a [`device!`] driver
+///
+/// The access mode `A` decides which of the methods are available. Register
+/// addresses are single bytes.
+///
+/// [`device!`]: macro@crate::device
+pub struct Register<'a, T, A> {
+    connection: &'a Connection,
+    address: u8,
+    endian: Endian,
+    reset: Option<T>,
+    access: PhantomData<A>,
+}
+
+impl<'a, T: RegisterValue, A: Access> Register<'a, T, A> {
+    /// Creates a register handle
+    ///
+    /// # Arguments
+    ///
+    /// `connection` - Connection to the device
+    /// `address` - Register address
+    /// `endian` - Byte order of the register value
+    /// `reset` - Value of the register after reset, if known
+    pub fn new(connection: &'a Connection, address: u8, endian: Endian, reset: Option<T>) -> Self {
+        Self {
+            connection,
+            address,
+            endian,
+            reset,
+            access: PhantomData,
+        }
+    }
+
+    /// Register address
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Value of the register after reset, if known
+    pub fn reset_value(&self) -> Option<T> {
+        self.reset
+    }
+
+    /// Writes the reset value if the register is writable and has one
+    #[doc(hidden)]
+    pub fn restore(&self) -> I2cResult<()> {
+        match self.reset {
+            Some(value) if A::WRITABLE => {
+                self.connection
+                    .write_register(self.address, value, self.endian)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T: RegisterValue, A: Readable> Register<'_, T, A> {
+    /// Reads the register
+    pub fn read(&self) -> I2cResult<T> {
+        self.connection.read_register(self.address, self.endian)
+    }
+}
+
+impl<T: RegisterValue, A: Writable> Register<'_, T, A> {
+    /// Writes the register
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Value to write
+    pub fn write(&self, value: T) -> I2cResult<()> {
+        self.connection
+            .write_register(self.address, value, self.endian)
+    }
+
+    /// Writes the register's reset value
+    ///
+    /// Registers without a reset value are left alone.
+    pub fn reset(&self) -> I2cResult<()> {
+        self.restore()
+    }
+}
+
+impl<T: RegisterValue, A: Readable + Writable> Register<'_, T, A> {
+    /// Reads the register, applies `f` and writes the result back under
+    /// [`Connection::exclusive`], returning the new value
+    ///
+    /// # Arguments
+    ///
+    /// `f` - Function computing the new value from the current one
+    pub fn update<F>(&self, f: F) -> I2cResult<T>
+    where
+        F: FnOnce(T) -> T,
+    {
+        self.connection.exclusive(|_| {
+            let value = f(self.read()?);
+            self.write(value)?;
+            Ok(value)
+        })
+    }
+}
+
+/// Defines a driver for a register-based device
+///
+/// Every register is given as `name: Type @ address, Access` or
+/// `name: Type @ address, Access = reset`. The type is one of the
+/// [`RegisterValue`] integer types and sets the register's width, and the
+/// access mode is [`ReadOnly`], [`WriteOnly`] or [`ReadWrite`]. Multi-byte
+/// registers are big endian unless an [`Endian`] variant follows the
+/// struct name, e.g. `pub struct Lis3mdl: Little { .. }`.
+///
+/// Addresses are single bytes; devices with 16-bit or wider register
+/// addresses need their registers accessed through
+/// [`RegisterAddress`](crate::RegisterAddress) and
+/// [`Connection::read_register`] instead.
+///
+/// The driver wraps a [`Connection`] and has a method per register
+/// returning a [`Register`], through which the register is read, written,
+/// updated or reset as its access mode allows. `reset_all` on the driver
+/// writes the reset value of every writable register that has one, and the
+/// connection is borrowed through `AsRef<Connection>`.
+///
+/// Register methods share the driver's namespace, so `new`, `from_path`,
+/// `into_inner`, `reset_all` and `ENDIAN` are reserved and cannot be used as
+/// register names. Names such as `reset` or `connection` are fine.
+#[macro_export]
+macro_rules! device {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident $(: $endian:ident)? {
+            $(
+                $(#[$rmeta:meta])*
+                $reg:ident: $ty:ident @ $addr:literal, $access:ident $(= $reset:expr)?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            connection: $crate::Connection,
+        }
+
+        impl $name {
+            /// Byte order of multi-byte registers
+            pub const ENDIAN: $crate::Endian = $crate::device!(@endian $($endian)?);
+
+            /// Creates a driver talking to the device through `connection`
+            pub fn new(connection: $crate::Connection) -> Self {
+                Self { connection }
+            }
+
+            /// Creates a driver for the device at `slave` on the I2C
+            /// adapter at `path`
+            pub fn from_path(path: &str, slave: impl Into<$crate::Address>) -> Self {
+                Self::new($crate::Connection::from_path(path, slave))
+            }
+
+            /// Gives back the connection to the device
+            pub fn into_inner(self) -> $crate::Connection {
+                self.connection
+            }
+
+            /// Writes the reset value of every writable register that has
+            /// one, in the order the registers are defined
+            pub fn reset_all(&self) -> $crate::I2cResult<()> {
+                $(self.$reg().restore()?;)*
+                Ok(())
+            }
+
+            $(
+                $(#[$rmeta])*
+                pub fn $reg(&self) -> $crate::device::Register<'_, $ty, $crate::device::$access> {
+                    $crate::device::Register::new(
+                        &self.connection,
+                        $addr,
+                        Self::ENDIAN,
+                        $crate::device!(@reset $ty $(, $reset)?),
+                    )
+                }
+            )*
+        }
+
+        impl ::core::convert::AsRef<$crate::Connection> for $name {
+            fn as_ref(&self) -> &$crate::Connection {
+                &self.connection
+            }
+        }
+    };
+    (@endian) => { $crate::Endian::Big };
+    (@endian $endian:ident) => { $crate::Endian::$endian };
+    (@reset $ty:ident) => { None };
+    (@reset $ty:ident, $reset:expr) => { Some($reset as $ty) };
+}
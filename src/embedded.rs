@@ -0,0 +1,360 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! embedded-hal support
+//!
+//! Available with the `embedded-hal` feature. [`I2cAdapter`] implements
+//! `embedded_hal::i2c::I2c` for both 7-bit and 10-bit addresses, so
+//! embedded-hal device drivers can run on a Linux I2C adapter:
+//!
+//! ```no_run
+//! use embedded_hal::i2c::I2c;
+//! use i2c_rs::embedded::I2cAdapter;
+//!
+//! let mut adapter = I2cAdapter::new("/dev/i2c-1");
+//! let mut id = [0; 2];
+//! adapter.write_read(0x48u8, &[0x0F], &mut id).unwrap();
+//! ```
+//!
+//! With the `embedded-hal-async` feature, [`AsyncI2cAdapter`] implements
+//! `embedded_hal_async::i2c::I2c` on top of tokio.
+
+use crate::{is_stale_handle, Address, I2cError, I2cErrorKind, I2cResult};
+use embedded_hal::i2c::{
+    self, ErrorKind, ErrorType, NoAcknowledgeSource, Operation, SevenBitAddress, TenBitAddress,
+};
+use i2c_linux::{Functionality, I2c, Message};
+use std::fs::File;
+use std::io::{Error, ErrorKind as IoErrorKind, Result};
+use std::sync::{Mutex, MutexGuard};
+#[cfg(feature = "embedded-hal-async")]
+use {crate::asynchronous::joined, std::sync::Arc, tokio::task};
+
+/// A Linux I2C adapter, addressing a slave per transaction
+///
+/// Every transaction is issued as a single `I2C_RDWR` ioctl, so the adapter
+/// must support raw I2C. Adjacent operations of the same type are merged
+/// into one message, as embedded-hal requires, which means the kernel's
+/// limit of 42 messages per ioctl applies to the number of alternations
+/// between writes and reads.
+///
+/// Like [`I2CStream`](crate::I2CStream), the adapter is opened on first use
+/// and reopened if its handle goes stale.
+pub struct I2cAdapter {
+    path: String,
+    handle: Mutex<Option<Handle>>,
+}
+
+struct Handle {
+    i2c: I2c<File>,
+    functionality: Functionality,
+}
+
+impl I2cAdapter {
+    /// Creates new I2cAdapter instance
+    ///
+    /// The adapter is not opened until the first transaction.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - File system path to I2C adapter
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            handle: Mutex::new(None),
+        }
+    }
+
+    /// Creates new I2cAdapter instance and opens the adapter immediately
+    ///
+    /// # Arguments
+    ///
+    /// `path` - File system path to I2C adapter
+    pub fn open(path: &str) -> I2cResult<Self> {
+        let adapter = Self::new(path);
+        adapter.with_handle(None, |_| Ok(()))?;
+        Ok(adapter)
+    }
+
+    /// Path to the I2C adapter
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Functionality supported by the adapter
+    pub fn functionality(&self) -> I2cResult<Functionality> {
+        self.with_handle(None, |h| Ok(h.functionality))
+    }
+
+    /// Runs a sequence of operations against a slave as one transaction
+    ///
+    /// This is the transaction of `embedded_hal::i2c::I2c`, taking either
+    /// kind of [`Address`].
+    ///
+    /// # Arguments
+    ///
+    /// `address` - Slave address, either a plain 7-bit address or an
+    ///             [`Address`]
+    /// `operations` - Writes and reads making up the transaction
+    pub fn transaction(
+        &self,
+        address: impl Into<Address>,
+        operations: &mut [Operation<'_>],
+    ) -> I2cResult<()> {
+        let mut chunks = Chunk::merge(operations);
+        self.transfer(address.into(), &mut chunks)?;
+        Chunk::scatter(chunks, operations);
+        Ok(())
+    }
+
+    /// Carries out merged operations in a single `I2C_RDWR` ioctl
+    pub(crate) fn transfer(&self, address: Address, chunks: &mut [Chunk]) -> I2cResult<()> {
+        self.with_handle(Some(address), |h| {
+            address.validate()?;
+            if address.is_ten_bit() && !h.functionality.contains(Functionality::TENBIT_ADDR) {
+                return Err(Error::new(
+                    IoErrorKind::Unsupported,
+                    "adapter does not support 10-bit addressing",
+                ));
+            }
+            if chunks.is_empty() {
+                return Ok(());
+            }
+            let mut messages: Vec<Message<'_>> = chunks
+                .iter_mut()
+                .map(|chunk| match chunk {
+                    Chunk::Write(data) => Message::Write {
+                        address: address.value(),
+                        data,
+                        flags: address.write_flags(),
+                    },
+                    Chunk::Read(data) => Message::Read {
+                        address: address.value(),
+                        data,
+                        flags: address.read_flags(),
+                    },
+                })
+                .collect();
+            h.i2c.i2c_transfer(&mut messages)
+        })
+    }
+
+    fn lock_handle(&self) -> MutexGuard<'_, Option<Handle>> {
+        self.handle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn open_handle(&self) -> Result<Handle> {
+        let i2c = I2c::from_path(&self.path)?;
+        let functionality = i2c.i2c_functionality()?;
+        if !functionality.contains(Functionality::I2C) {
+            return Err(Error::new(
+                IoErrorKind::Unsupported,
+                format!(
+                    "{} does not support raw I2C transfers (functionality: {:?})",
+                    self.path, functionality
+                ),
+            ));
+        }
+        Ok(Handle { i2c, functionality })
+    }
+
+    /// Runs `f` against the adapter handle, opening it first if necessary
+    /// and dropping it if it has gone stale
+    fn with_handle<T, F>(&self, address: Option<Address>, f: F) -> I2cResult<T>
+    where
+        F: FnOnce(&mut Handle) -> Result<T>,
+    {
+        let mut handle = self.lock_handle();
+        let result = match *handle {
+            Some(ref mut h) => f(h),
+            None => self.open_handle().and_then(|h| f(handle.insert(h))),
+        };
+        if let Err(ref e) = result {
+            if is_stale_handle(e) {
+                *handle = None;
+            }
+        }
+        result.map_err(|e| I2cError::from(e).with_context(Some(&self.path), address, None))
+    }
+}
+
+/// Adjacent operations of the same type, merged into one message
+pub(crate) enum Chunk {
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+}
+
+impl Chunk {
+    /// Merges adjacent operations of the same type, copying the data to
+    /// write and allocating room for the data to read
+    pub(crate) fn merge(operations: &[Operation<'_>]) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        for operation in operations {
+            match (operation, chunks.last_mut()) {
+                (Operation::Write(data), Some(Chunk::Write(buf))) => buf.extend_from_slice(data),
+                (Operation::Write(data), _) => chunks.push(Chunk::Write(data.to_vec())),
+                (Operation::Read(data), Some(Chunk::Read(buf))) => {
+                    buf.resize(buf.len() + data.len(), 0)
+                }
+                (Operation::Read(data), _) => chunks.push(Chunk::Read(vec![0; data.len()])),
+            }
+        }
+        chunks
+    }
+
+    /// Copies the data read into the read operations the chunks were
+    /// merged from
+    pub(crate) fn scatter(chunks: Vec<Chunk>, operations: &mut [Operation<'_>]) {
+        let mut read = chunks
+            .into_iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::Read(data) => Some(data),
+                Chunk::Write(_) => None,
+            })
+            .flatten();
+        for operation in operations {
+            if let Operation::Read(data) = operation {
+                for byte in data.iter_mut() {
+                    *byte = read.next().unwrap_or_default();
+                }
+            }
+        }
+    }
+}
+
+impl i2c::Error for I2cError {
+    fn kind(&self) -> ErrorKind {
+        match I2cError::kind(self) {
+            I2cErrorKind::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            I2cErrorKind::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorType for I2cAdapter {
+    type Error = I2cError;
+}
+
+impl i2c::I2c<SevenBitAddress> for I2cAdapter {
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> I2cResult<()> {
+        I2cAdapter::transaction(self, Address::SevenBit(address.into()), operations)
+    }
+}
+
+impl i2c::I2c<TenBitAddress> for I2cAdapter {
+    fn transaction(
+        &mut self,
+        address: TenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> I2cResult<()> {
+        I2cAdapter::transaction(self, Address::TenBit(address), operations)
+    }
+}
+
+/// An [`I2cAdapter`] for async embedded-hal drivers
+///
+/// Transactions run on tokio's blocking thread pool, so they don't block
+/// the executor. The operations' data is copied to and from the pool, and
+/// each transaction is still a single `I2C_RDWR` ioctl.
+///
+/// Dropping a transaction's future doesn't interrupt an ioctl that has
+/// already started; it runs to completion and its data is discarded.
+#[cfg(feature = "embedded-hal-async")]
+#[derive(Clone)]
+pub struct AsyncI2cAdapter {
+    adapter: Arc<I2cAdapter>,
+}
+
+#[cfg(feature = "embedded-hal-async")]
+impl AsyncI2cAdapter {
+    /// Creates new AsyncI2cAdapter instance
+    ///
+    /// The adapter is not opened until the first transaction.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - File system path to I2C adapter
+    pub fn new(path: &str) -> Self {
+        Self::from(I2cAdapter::new(path))
+    }
+
+    /// Runs a sequence of operations against a slave as one transaction
+    ///
+    /// # Arguments
+    ///
+    /// `address` - Slave address, either a plain 7-bit address or an
+    ///             [`Address`]
+    /// `operations` - Writes and reads making up the transaction
+    pub async fn transaction(
+        &self,
+        address: impl Into<Address>,
+        operations: &mut [Operation<'_>],
+    ) -> I2cResult<()> {
+        let address = address.into();
+        let mut chunks = Chunk::merge(operations);
+        let adapter = self.adapter.clone();
+        let chunks = joined(
+            task::spawn_blocking(move || {
+                adapter.transfer(address, &mut chunks)?;
+                Ok(chunks)
+            })
+            .await,
+        )?;
+        Chunk::scatter(chunks, operations);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal-async")]
+impl From<I2cAdapter> for AsyncI2cAdapter {
+    fn from(adapter: I2cAdapter) -> Self {
+        Self {
+            adapter: Arc::new(adapter),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-async")]
+impl ErrorType for AsyncI2cAdapter {
+    type Error = I2cError;
+}
+
+#[cfg(feature = "embedded-hal-async")]
+impl embedded_hal_async::i2c::I2c<SevenBitAddress> for AsyncI2cAdapter {
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> I2cResult<()> {
+        AsyncI2cAdapter::transaction(self, Address::SevenBit(address.into()), operations).await
+    }
+}
+
+#[cfg(feature = "embedded-hal-async")]
+impl embedded_hal_async::i2c::I2c<TenBitAddress> for AsyncI2cAdapter {
+    async fn transaction(
+        &mut self,
+        address: TenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> I2cResult<()> {
+        AsyncI2cAdapter::transaction(self, Address::TenBit(address), operations).await
+    }
+}
@@ -0,0 +1,479 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! I2C error type
+
+use crate::{Address, PecMismatch};
+use std::fmt;
+use std::io;
+
+/// Result of an I2C operation
+pub type I2cResult<T> = Result<T, I2cError>;
+
+/// Where an error occurred, as far as it is known
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ErrorContext {
+    /// Path to the I2C adapter
+    pub path: Option<String>,
+    /// Slave address
+    pub address: Option<Address>,
+    /// Command/register byte of the transaction
+    pub command: Option<u8>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        if let Some(ref path) = self.path {
+            write!(f, "{}", path)?;
+            sep = " ";
+        }
+        if let Some(address) = self.address {
+            write!(f, "{}slave {}", sep, address)?;
+            sep = " ";
+        }
+        if let Some(command) = self.command {
+            write!(f, "{}command {:#04x}", sep, command)?;
+        }
+        Ok(())
+    }
+}
+
+/// Category of an [`I2cError`], without its details
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum I2cErrorKind {
+    /// See [`I2cError::Nack`]
+    Nack,
+    /// See [`I2cError::ArbitrationLost`]
+    ArbitrationLost,
+    /// See [`I2cError::Timeout`]
+    Timeout,
+    /// See [`I2cError::InvalidArgument`]
+    InvalidArgument,
+    /// See [`I2cError::AdapterNotFound`]
+    AdapterNotFound,
+    /// See [`I2cError::Unsupported`]
+    Unsupported,
+    /// See [`I2cError::Pec`]
+    Pec,
+    /// See [`I2cError::LockTimeout`]
+    LockTimeout,
+    /// See [`I2cError::Parse`]
+    Parse,
+    /// See [`I2cError::VerifyMismatch`]
+    VerifyMismatch,
+    /// See [`I2cError::Io`]
+    Io,
+}
+
+impl I2cErrorKind {
+    /// Short lowercase name of the kind
+    pub fn as_str(self) -> &'static str {
+        match self {
+            I2cErrorKind::Nack => "nack",
+            I2cErrorKind::ArbitrationLost => "arbitration_lost",
+            I2cErrorKind::Timeout => "timeout",
+            I2cErrorKind::InvalidArgument => "invalid_argument",
+            I2cErrorKind::AdapterNotFound => "adapter_not_found",
+            I2cErrorKind::Unsupported => "unsupported",
+            I2cErrorKind::Pec => "pec",
+            I2cErrorKind::LockTimeout => "lock_timeout",
+            I2cErrorKind::Parse => "parse",
+            I2cErrorKind::VerifyMismatch => "verify_mismatch",
+            I2cErrorKind::Io => "io",
+        }
+    }
+}
+
+impl I2cErrorKind {
+    /// Classifies an `io::Error`, by its [`I2cError`] if it wraps one and
+    /// otherwise by its OS error number or `io::ErrorKind`
+    pub fn of(err: &io::Error) -> Self {
+        if let Some(inner) = err.get_ref() {
+            if let Some(err) = inner.downcast_ref::<I2cError>() {
+                return err.kind();
+            }
+            if inner.is::<PecMismatch>() {
+                return I2cErrorKind::Pec;
+            }
+        }
+        if let Some(errno) = err.raw_os_error() {
+            return match errno {
+                libc::ENXIO | libc::EREMOTEIO => I2cErrorKind::Nack,
+                libc::EAGAIN => I2cErrorKind::ArbitrationLost,
+                libc::ETIMEDOUT => I2cErrorKind::Timeout,
+                libc::EINVAL => I2cErrorKind::InvalidArgument,
+                libc::ENOENT | libc::ENODEV => I2cErrorKind::AdapterNotFound,
+                libc::EOPNOTSUPP => I2cErrorKind::Unsupported,
+                libc::EBADMSG => I2cErrorKind::Pec,
+                _ => I2cErrorKind::Io,
+            };
+        }
+        match err.kind() {
+            io::ErrorKind::InvalidInput => I2cErrorKind::InvalidArgument,
+            io::ErrorKind::Unsupported => I2cErrorKind::Unsupported,
+            io::ErrorKind::TimedOut => I2cErrorKind::Timeout,
+            _ => I2cErrorKind::Io,
+        }
+    }
+}
+
+impl fmt::Display for I2cErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for I2cErrorKind {
+    type Err = ();
+
+    /// Parses the name returned by [`I2cErrorKind::as_str`]
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(match s {
+            "nack" => I2cErrorKind::Nack,
+            "arbitration_lost" => I2cErrorKind::ArbitrationLost,
+            "timeout" => I2cErrorKind::Timeout,
+            "invalid_argument" => I2cErrorKind::InvalidArgument,
+            "adapter_not_found" => I2cErrorKind::AdapterNotFound,
+            "unsupported" => I2cErrorKind::Unsupported,
+            "pec" => I2cErrorKind::Pec,
+            "lock_timeout" => I2cErrorKind::LockTimeout,
+            "parse" => I2cErrorKind::Parse,
+            "verify_mismatch" => I2cErrorKind::VerifyMismatch,
+            "io" => I2cErrorKind::Io,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// I2C error
+///
+/// Errors convert to and from `std::io::Error`, so they pass through
+/// `hal_stream::Stream` implementations unchanged: an `io::Error` created
+/// from an `I2cError` converts back into the original `I2cError`, and
+/// errors from the OS are classified by their error number.
+#[derive(Debug)]
+pub enum I2cError {
+    /// The slave did not acknowledge (`ENXIO`, `EREMOTEIO`)
+    Nack(ErrorContext),
+    /// Bus arbitration was lost to another master (`EAGAIN`)
+    ArbitrationLost(ErrorContext),
+    /// The transaction timed out (`ETIMEDOUT`)
+    Timeout(ErrorContext),
+    /// The transaction was malformed or not valid for the slave (`EINVAL`)
+    InvalidArgument(ErrorContext, String),
+    /// The I2C adapter does not exist (`ENOENT`, `ENODEV`)
+    AdapterNotFound(ErrorContext),
+    /// The adapter does not support the transaction (`EOPNOTSUPP`)
+    Unsupported(ErrorContext, String),
+    /// SMBus Packet Error Checking failed (`EBADMSG`). The mismatching
+    /// checksums are only known if PEC was checked in software.
+    Pec(ErrorContext, Option<PecMismatch>),
+    /// The bus lock file given in the `String` could not be locked within
+    /// the lock timeout, because another process held it
+    LockTimeout(ErrorContext, String),
+    /// The slave's response could not be decoded, e.g. because it was
+    /// shorter than expected
+    Parse(ErrorContext, String),
+    /// A register read back after writing it did not hold the written
+    /// value
+    VerifyMismatch(ErrorContext, String),
+    /// Any other I/O error
+    Io(ErrorContext, io::Error),
+}
+
+impl I2cError {
+    /// Category of the error
+    pub fn kind(&self) -> I2cErrorKind {
+        match self {
+            I2cError::Nack(_) => I2cErrorKind::Nack,
+            I2cError::ArbitrationLost(_) => I2cErrorKind::ArbitrationLost,
+            I2cError::Timeout(_) => I2cErrorKind::Timeout,
+            I2cError::InvalidArgument(..) => I2cErrorKind::InvalidArgument,
+            I2cError::AdapterNotFound(_) => I2cErrorKind::AdapterNotFound,
+            I2cError::Unsupported(..) => I2cErrorKind::Unsupported,
+            I2cError::Pec(..) => I2cErrorKind::Pec,
+            I2cError::LockTimeout(..) => I2cErrorKind::LockTimeout,
+            I2cError::Parse(..) => I2cErrorKind::Parse,
+            I2cError::VerifyMismatch(..) => I2cErrorKind::VerifyMismatch,
+            I2cError::Io(..) => I2cErrorKind::Io,
+        }
+    }
+
+    /// Creates an error of the given kind without context
+    ///
+    /// `message` is kept for the kinds which carry one (the lock file path
+    /// for [`I2cErrorKind::LockTimeout`]) and becomes the `io::Error` of
+    /// [`I2cErrorKind::Io`].
+    pub fn new(kind: I2cErrorKind, message: &str) -> Self {
+        let context = ErrorContext::default();
+        match kind {
+            I2cErrorKind::Nack => I2cError::Nack(context),
+            I2cErrorKind::ArbitrationLost => I2cError::ArbitrationLost(context),
+            I2cErrorKind::Timeout => I2cError::Timeout(context),
+            I2cErrorKind::InvalidArgument => {
+                I2cError::InvalidArgument(context, message.to_string())
+            }
+            I2cErrorKind::AdapterNotFound => I2cError::AdapterNotFound(context),
+            I2cErrorKind::Unsupported => I2cError::Unsupported(context, message.to_string()),
+            I2cErrorKind::Pec => I2cError::Pec(context, None),
+            I2cErrorKind::LockTimeout => I2cError::LockTimeout(context, message.to_string()),
+            I2cErrorKind::Parse => I2cError::Parse(context, message.to_string()),
+            I2cErrorKind::VerifyMismatch => I2cError::VerifyMismatch(context, message.to_string()),
+            I2cErrorKind::Io => I2cError::Io(context, io::Error::other(message)),
+        }
+    }
+
+    /// What went wrong, without the context: the message
+    /// [`I2cError::new`] takes for the kinds which carry one, and a
+    /// description otherwise
+    pub(crate) fn message(&self) -> String {
+        match self {
+            I2cError::InvalidArgument(_, msg)
+            | I2cError::Unsupported(_, msg)
+            | I2cError::LockTimeout(_, msg)
+            | I2cError::Parse(_, msg)
+            | I2cError::VerifyMismatch(_, msg) => msg.clone(),
+            I2cError::Pec(_, Some(mismatch)) => mismatch.to_string(),
+            I2cError::Io(_, e) => e.to_string(),
+            _ => I2cError::new(self.kind(), "").to_string(),
+        }
+    }
+
+    /// Where the error occurred
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            I2cError::Nack(context)
+            | I2cError::ArbitrationLost(context)
+            | I2cError::Timeout(context)
+            | I2cError::InvalidArgument(context, _)
+            | I2cError::AdapterNotFound(context)
+            | I2cError::Unsupported(context, _)
+            | I2cError::Pec(context, _)
+            | I2cError::LockTimeout(context, _)
+            | I2cError::Parse(context, _)
+            | I2cError::VerifyMismatch(context, _)
+            | I2cError::Io(context, _) => context,
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut ErrorContext {
+        match self {
+            I2cError::Nack(context)
+            | I2cError::ArbitrationLost(context)
+            | I2cError::Timeout(context)
+            | I2cError::InvalidArgument(context, _)
+            | I2cError::AdapterNotFound(context)
+            | I2cError::Unsupported(context, _)
+            | I2cError::Pec(context, _)
+            | I2cError::LockTimeout(context, _)
+            | I2cError::Parse(context, _)
+            | I2cError::VerifyMismatch(context, _)
+            | I2cError::Io(context, _) => context,
+        }
+    }
+
+    /// Fills in whichever parts of the context are not yet known
+    pub(crate) fn with_context(
+        mut self,
+        path: Option<&str>,
+        address: Option<Address>,
+        command: Option<u8>,
+    ) -> Self {
+        let context = self.context_mut();
+        if context.path.is_none() {
+            context.path = path.map(str::to_string);
+        }
+        context.address = context.address.or(address);
+        context.command = context.command.or(command);
+        self
+    }
+
+    /// `std::io::ErrorKind` used when converting into an `io::Error`
+    fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            I2cError::Nack(_) => io::ErrorKind::Other,
+            I2cError::ArbitrationLost(_) => io::ErrorKind::WouldBlock,
+            I2cError::Timeout(_) => io::ErrorKind::TimedOut,
+            I2cError::InvalidArgument(..) => io::ErrorKind::InvalidInput,
+            I2cError::AdapterNotFound(_) => io::ErrorKind::NotFound,
+            I2cError::Unsupported(..) => io::ErrorKind::Unsupported,
+            I2cError::Pec(..) => io::ErrorKind::InvalidData,
+            I2cError::LockTimeout(..) => io::ErrorKind::TimedOut,
+            I2cError::Parse(..) => io::ErrorKind::InvalidData,
+            I2cError::VerifyMismatch(..) => io::ErrorKind::InvalidData,
+            I2cError::Io(_, e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for I2cError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            I2cError::Nack(_) => write!(f, "slave did not acknowledge")?,
+            I2cError::ArbitrationLost(_) => write!(f, "bus arbitration lost")?,
+            I2cError::Timeout(_) => write!(f, "transaction timed out")?,
+            I2cError::InvalidArgument(_, msg) => write!(f, "invalid argument: {}", msg)?,
+            I2cError::AdapterNotFound(_) => write!(f, "I2C adapter not found")?,
+            I2cError::Unsupported(_, msg) => write!(f, "unsupported: {}", msg)?,
+            I2cError::Pec(_, Some(mismatch)) => write!(f, "{}", mismatch)?,
+            I2cError::Pec(_, None) => write!(f, "PEC mismatch")?,
+            I2cError::LockTimeout(_, path) => write!(f, "timed out waiting for bus lock {}", path)?,
+            I2cError::Parse(_, msg) => write!(f, "malformed response: {}", msg)?,
+            I2cError::VerifyMismatch(_, msg) => write!(f, "verification failed: {}", msg)?,
+            I2cError::Io(_, e) => write!(f, "{}", e)?,
+        }
+        let context = self.context();
+        if *context != ErrorContext::default() {
+            write!(f, " ({})", context)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for I2cError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            I2cError::Pec(_, Some(mismatch)) => Some(mismatch),
+            I2cError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<I2cError> for io::Error {
+    fn from(err: I2cError) -> Self {
+        io::Error::new(err.io_kind(), err)
+    }
+}
+
+impl From<io::Error> for I2cError {
+    fn from(err: io::Error) -> Self {
+        let typed = err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<I2cError>() || inner.is::<PecMismatch>());
+        if typed {
+            return match err.into_inner().unwrap().downcast::<I2cError>() {
+                Ok(err) => *err,
+                Err(inner) => {
+                    let mismatch = inner.downcast::<PecMismatch>().ok().map(|m| *m);
+                    I2cError::Pec(ErrorContext::default(), mismatch)
+                }
+            };
+        }
+        match I2cErrorKind::of(&err) {
+            I2cErrorKind::Io => I2cError::Io(ErrorContext::default(), err),
+            kind => I2cError::new(kind, &err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [I2cErrorKind; 11] = [
+        I2cErrorKind::Nack,
+        I2cErrorKind::ArbitrationLost,
+        I2cErrorKind::Timeout,
+        I2cErrorKind::InvalidArgument,
+        I2cErrorKind::AdapterNotFound,
+        I2cErrorKind::Unsupported,
+        I2cErrorKind::Pec,
+        I2cErrorKind::LockTimeout,
+        I2cErrorKind::Parse,
+        I2cErrorKind::VerifyMismatch,
+        I2cErrorKind::Io,
+    ];
+
+    #[test]
+    fn errno_mapping() {
+        let table = [
+            (libc::ENXIO, I2cErrorKind::Nack),
+            (libc::EREMOTEIO, I2cErrorKind::Nack),
+            (libc::EAGAIN, I2cErrorKind::ArbitrationLost),
+            (libc::ETIMEDOUT, I2cErrorKind::Timeout),
+            (libc::EINVAL, I2cErrorKind::InvalidArgument),
+            (libc::ENOENT, I2cErrorKind::AdapterNotFound),
+            (libc::ENODEV, I2cErrorKind::AdapterNotFound),
+            (libc::EOPNOTSUPP, I2cErrorKind::Unsupported),
+            (libc::EBADMSG, I2cErrorKind::Pec),
+            (libc::EIO, I2cErrorKind::Io),
+        ];
+        for &(errno, kind) in &table {
+            let err = io::Error::from_raw_os_error(errno);
+            assert_eq!(I2cErrorKind::of(&err), kind, "errno {}", errno);
+            assert_eq!(I2cError::from(err).kind(), kind, "errno {}", errno);
+        }
+    }
+
+    #[test]
+    fn kind_names_round_trip() {
+        for &kind in &KINDS {
+            assert_eq!(kind.as_str().parse::<I2cErrorKind>(), Ok(kind));
+        }
+        assert!("".parse::<I2cErrorKind>().is_err());
+    }
+
+    #[test]
+    fn io_error_round_trip() {
+        for &kind in &KINDS {
+            let err = I2cError::new(kind, "message").with_context(
+                Some("/dev/i2c-1"),
+                Some(Address::SevenBit(0x48)),
+                Some(0x10),
+            );
+            let expected = err.to_string();
+            let io_err = io::Error::from(err);
+            assert_eq!(I2cErrorKind::of(&io_err), kind);
+
+            let err = I2cError::from(io_err);
+            assert_eq!(err.kind(), kind);
+            assert_eq!(err.to_string(), expected);
+            assert_eq!(err.context().path.as_deref(), Some("/dev/i2c-1"));
+            assert_eq!(err.context().address, Some(Address::SevenBit(0x48)));
+            assert_eq!(err.context().command, Some(0x10));
+        }
+    }
+
+    #[test]
+    fn pec_mismatch_round_trip() {
+        let mismatch = PecMismatch {
+            expected: 0x30,
+            received: 0x31,
+        };
+        let io_err = io::Error::new(io::ErrorKind::InvalidData, mismatch);
+        assert_eq!(I2cErrorKind::of(&io_err), I2cErrorKind::Pec);
+        match I2cError::from(io_err) {
+            I2cError::Pec(_, Some(m)) => assert_eq!(m.received, 0x31),
+            err => panic!("unexpected {:?}", err),
+        }
+    }
+
+    #[test]
+    fn io_kind_fallback() {
+        let table = [
+            (io::ErrorKind::InvalidInput, I2cErrorKind::InvalidArgument),
+            (io::ErrorKind::Unsupported, I2cErrorKind::Unsupported),
+            (io::ErrorKind::TimedOut, I2cErrorKind::Timeout),
+            (io::ErrorKind::BrokenPipe, I2cErrorKind::Io),
+        ];
+        for &(io_kind, kind) in &table {
+            let err = io::Error::new(io_kind, "message");
+            assert_eq!(I2cErrorKind::of(&err), kind, "{:?}", io_kind);
+        }
+    }
+}
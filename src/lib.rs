@@ -24,149 +24,905 @@
 
 //! I2C device connection abstractions
 
-use i2c_linux::{I2c,Message,WriteFlags,ReadFlags};
-use std::io::Result;
+use i2c_linux::{I2c,Message,SmbusReadWrite,WriteFlags,ReadFlags};
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{BitAnd, BitOr, Not};
+use std::sync::{Mutex, MutexGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use hal_stream::Stream;
 
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod bus;
+pub mod device;
+#[cfg(feature = "embedded-hal")]
+pub mod embedded;
+pub mod error;
+mod lock;
+pub mod mock;
+pub mod pec;
+pub mod record;
+pub mod register;
+pub mod retry;
+pub mod scan;
+pub mod sim;
+pub mod smbus;
+
+pub use bus::SharedBus;
+pub use error::{ErrorContext, I2cError, I2cErrorKind, I2cResult};
+pub use i2c_linux::Functionality;
+pub use mock::MockStream;
+pub use pec::PecMismatch;
+pub use record::{Recorder, Replay};
+pub use register::{Bitfield, Endian, RegisterAddress, RegisterValue};
+pub use retry::{Backoff, Retried, RetryPolicy};
+pub use scan::{scan, ScanEntry, ScanStatus};
+pub use smbus::SmbusStream;
+
+use lock::{FileLock, FileLockGuard};
+use smbus::Emulated;
+
+/// I2C slave address
+///
+/// Plain integers convert into 7-bit addresses.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Address {
+    /// 7-bit address (`0x00`..=`0x7F`)
+    SevenBit(u16),
+    /// 10-bit address (`0x000`..=`0x3FF`)
+    TenBit(u16),
+}
+
+impl Address {
+    /// Raw address value, without the 10-bit flag
+    pub fn value(self) -> u16 {
+        match self {
+            Address::SevenBit(address) | Address::TenBit(address) => address,
+        }
+    }
+
+    /// Whether this is a 10-bit address
+    pub fn is_ten_bit(self) -> bool {
+        matches!(self, Address::TenBit(_))
+    }
+
+    /// Checks that the address value fits in its address width
+    fn validate(self) -> Result<()> {
+        let max = if self.is_ten_bit() { 0x3FF } else { 0x7F };
+        if self.value() > max {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("I2C address {} is out of range", self),
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_flags(self) -> ReadFlags {
+        if self.is_ten_bit() {
+            ReadFlags::TENBIT_ADDR
+        } else {
+            ReadFlags::default()
+        }
+    }
+
+    fn write_flags(self) -> WriteFlags {
+        if self.is_ten_bit() {
+            WriteFlags::TENBIT_ADDR
+        } else {
+            WriteFlags::default()
+        }
+    }
+}
+
+impl From<u16> for Address {
+    fn from(address: u16) -> Self {
+        Address::SevenBit(address)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::SevenBit(address) => write!(f, "{:#04x}", address),
+            Address::TenBit(address) => write!(f, "{:#05x} (10-bit)", address),
+        }
+    }
+}
+
+/// How an [`I2CStream`] carries out plain writes and reads
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    /// Raw I2C messages, without length limits
+    RawI2c,
+    /// Equivalent SMBus transactions, for adapters which only implement
+    /// SMBus. Transfers are limited to 32 data bytes. Adapters without the
+    /// I2C block transactions are limited to writing one or two bytes after
+    /// the command byte and reading one or two bytes back, which map to the
+    /// SMBus byte and word data transactions.
+    SmbusEmulation,
+}
+
 /// An implementation of `i2c_hal::Stream` which uses the `i2c_linux` crate
 /// for communication with actual I2C hardware.
+///
+/// The underlying `/dev/i2c-N` handle is opened on first use and kept open
+/// for subsequent transactions. If a transaction fails because the handle has
+/// gone stale (e.g. the adapter was unbound), the handle is dropped and
+/// transparently reopened by the next transaction.
+///
+/// Transactions are issued as raw I2C messages, so writes are not limited in
+/// length. Adapters which only implement SMBus are driven through the
+/// equivalent SMBus transactions instead, subject to the SMBus 32 byte limit
+/// (see [`Strategy`]). The adapter's functionality is queried once when the
+/// handle is opened, and transactions it does not support fail up front with
+/// an `Unsupported` error naming the missing functionality.
+///
+/// With SMBus Packet Error Checking enabled (see [`I2CStream::with_pec`]),
+/// kernel PEC is turned on if the adapter supports it. The kernel only
+/// protects the transaction types it implements itself, so the raw and I2C
+/// block transfers used by this stream get their PEC byte appended and
+/// verified in software. A PEC byte that doesn't match is reported as a
+/// [`PecMismatch`].
+///
+/// Streams in different processes that share an adapter can serialize their
+/// transactions through an advisory lock file (see
+/// [`I2CStream::with_bus_lock`] and [`I2CStream::sequence`]).
 pub struct I2CStream {
     path: String,
-    slave: u16,
+    slave: Address,
+    pec: bool,
+    lock: Option<FileLock>,
+    handle: Mutex<Option<Handle>>,
+}
+
+/// An open device handle together with what we know about its adapter
+struct Handle {
+    i2c: I2c<File>,
+    address: Address,
+    functionality: Functionality,
+    strategy: Strategy,
+    /// Whether transfers carry a software-computed PEC byte
+    pec: bool,
+    /// Whether the kernel handles PEC for SMBus transactions
+    kernel_pec: bool,
+}
+
+impl Handle {
+    /// Writes `data` to the slave in a single transaction
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let mut buf;
+        let data = if self.pec && !data.is_empty() {
+            buf = data.to_vec();
+            buf.push(pec::write_pec(self.address, data));
+            &buf[..]
+        } else {
+            data
+        };
+
+        if self.strategy == Strategy::RawI2c {
+            let msg = Message::Write {
+                address: self.address.value(),
+                data,
+                flags: self.address.write_flags(),
+            };
+            return self.i2c.i2c_transfer(&mut [msg]);
+        }
+
+        match data {
+            [] => {
+                self.require(Functionality::SMBUS_QUICK)?;
+                self.i2c.smbus_write_quick(SmbusReadWrite::Write)
+            }
+            [byte] => {
+                self.require(Functionality::SMBUS_WRITE_BYTE)?;
+                self.i2c.smbus_write_byte(*byte)
+            }
+            [cmd, value] if self.without_block(Functionality::SMBUS_WRITE_I2C_BLOCK) => {
+                self.require(Functionality::SMBUS_WRITE_BYTE_DATA)?;
+                self.i2c.smbus_write_byte_data(*cmd, *value)
+            }
+            [cmd, lo, hi] if self.without_block(Functionality::SMBUS_WRITE_I2C_BLOCK) => {
+                self.require(Functionality::SMBUS_WRITE_WORD_DATA)?;
+                self.i2c
+                    .smbus_write_word_data(*cmd, u16::from_le_bytes([*lo, *hi]))
+            }
+            [cmd, rest @ ..] if rest.len() <= smbus::BLOCK_MAX => {
+                self.require(Functionality::SMBUS_WRITE_I2C_BLOCK)?;
+                self.i2c.i2c_write_block_data(*cmd, rest)
+            }
+            _ => Err(smbus_unsupported(&format!("{} byte write", data.len()))),
+        }
+    }
+
+    /// Writes `command` and reads `rx_len` bytes back in a single transaction
+    fn read(&mut self, command: &[u8], rx_len: usize) -> Result<Vec<u8>> {
+        let mut data = self.read_raw(command, rx_len + self.pec as usize)?;
+        if self.pec {
+            let crc = pec::read_pec(self.address, command, &data[..rx_len]);
+            let received = data.pop().unwrap();
+            if received != crc {
+                return Err(PecMismatch {
+                    expected: crc,
+                    received,
+                }
+                .into());
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes `command` and reads `rx_len` bytes back, without regard to PEC
+    fn read_raw(&mut self, command: &[u8], rx_len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; rx_len];
+
+        if self.strategy == Strategy::RawI2c {
+            let read = Message::Read {
+                address: self.address.value(),
+                data: &mut data,
+                flags: self.address.read_flags(),
+            };
+            if command.is_empty() {
+                self.i2c.i2c_transfer(&mut [read])?;
+            } else {
+                let write = Message::Write {
+                    address: self.address.value(),
+                    data: command,
+                    flags: self.address.write_flags(),
+                };
+                self.i2c.i2c_transfer(&mut [write, read])?;
+            }
+            return Ok(data);
+        }
+
+        match command {
+            [] if rx_len == 1 && !self.pec => {
+                self.require(Functionality::SMBUS_READ_BYTE)?;
+                data[0] = self.i2c.smbus_read_byte()?;
+            }
+            [cmd] if rx_len == 1 && self.without_block(Functionality::SMBUS_READ_I2C_BLOCK) => {
+                self.require(Functionality::SMBUS_READ_BYTE_DATA)?;
+                data[0] = self.i2c.smbus_read_byte_data(*cmd)?;
+            }
+            [cmd] if rx_len == 2 && self.without_block(Functionality::SMBUS_READ_I2C_BLOCK) => {
+                self.require(Functionality::SMBUS_READ_WORD_DATA)?;
+                let word = self.i2c.smbus_read_word_data(*cmd)?;
+                data.copy_from_slice(&word.to_le_bytes());
+            }
+            [cmd] if rx_len <= smbus::BLOCK_MAX => {
+                self.require(Functionality::SMBUS_READ_I2C_BLOCK)?;
+                self.i2c.i2c_read_block_data(*cmd, &mut data)?;
+            }
+            _ => {
+                return Err(smbus_unsupported(&format!(
+                    "{} byte read after a {} byte command",
+                    rx_len,
+                    command.len()
+                )))
+            }
+        }
+        Ok(data)
+    }
+
+    /// Whether short transfers have to fall back to the SMBus byte and word
+    /// data transactions because the adapter lacks the I2C `block`
+    /// transaction. The fallback isn't used with PEC, which is only verified
+    /// in software for I2C block transfers.
+    fn without_block(&self, block: Functionality) -> bool {
+        !self.pec && !self.functionality.contains(block)
+    }
+
+    /// Fails if the adapter lacks any of the `needed` functionality
+    fn require(&self, needed: Functionality) -> Result<()> {
+        let missing = needed - self.functionality;
+        if !missing.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("adapter does not support {:?}", missing),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Error for a transaction which can't be expressed in SMBus terms
+fn smbus_unsupported(what: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("adapter only supports SMBus, which cannot carry a {}", what),
+    )
 }
 
 impl I2CStream {
     /// Creates new I2CStream instance
     ///
+    /// The device handle is not opened until the first transaction.
+    ///
     /// # Arguments
     ///
     /// `path` - File system path to I2C device handle
-    /// `slave` - Address of slave I2C device
-    pub fn new(path: &str, slave: u16) -> Self {
+    /// `slave` - Address of slave I2C device, either a plain 7-bit address
+    ///           or an [`Address`]
+    pub fn new(path: &str, slave: impl Into<Address>) -> Self {
         Self {
             path: path.to_string(),
-            slave,
+            slave: slave.into(),
+            pec: false,
+            lock: None,
+            handle: Mutex::new(None),
+        }
+    }
+
+    /// Creates new I2CStream instance and opens the device handle
+    /// immediately
+    ///
+    /// Unlike [`I2CStream::new`], this fails straight away if the adapter
+    /// can't be opened or can't serve the slave, e.g. because it does not
+    /// support 10-bit addressing or supports neither raw I2C nor SMBus data
+    /// transfers.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - File system path to I2C device handle
+    /// `slave` - Address of slave I2C device, either a plain 7-bit address
+    ///           or an [`Address`]
+    pub fn open(path: &str, slave: impl Into<Address>) -> Result<Self> {
+        let stream = Self::new(path, slave);
+        stream.reopen()?;
+        Ok(stream)
+    }
+
+    /// Enables or disables SMBus Packet Error Checking
+    ///
+    /// # Arguments
+    ///
+    /// `pec` - Whether transactions should carry a PEC byte
+    pub fn with_pec(mut self, pec: bool) -> Self {
+        self.pec = pec;
+        self
+    }
+
+    /// Takes an advisory lock on a lock file around every transaction
+    ///
+    /// The lock is an exclusive `flock` on `lock_path`, which is created if
+    /// it doesn't exist. Every process accessing the bus should use the same
+    /// lock file for it, e.g. `/var/lock/i2c-1.lock`. Use
+    /// [`I2CStream::sequence`] to hold the lock across several transactions,
+    /// and [`Connection::from_stream`] to keep the lock held across
+    /// [`Connection::exclusive`] sequences.
+    ///
+    /// A transaction fails with [`I2cError::LockTimeout`] if the lock can't
+    /// be taken within `timeout`.
+    ///
+    /// # Arguments
+    ///
+    /// `lock_path` - Path to the lock file for this bus
+    /// `timeout` - How long to wait for another process to release the lock
+    pub fn with_bus_lock(mut self, lock_path: &str, timeout: Duration) -> Self {
+        self.lock = Some(FileLock::new(lock_path, timeout));
+        self
+    }
+
+    /// Runs `f` while holding the bus lock, so that the transactions made
+    /// by `f` on this stream can't be interleaved with those of other
+    /// processes
+    ///
+    /// Without a bus lock (see [`I2CStream::with_bus_lock`]) this just
+    /// runs `f`.
+    pub fn sequence<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Self) -> Result<T>,
+    {
+        let _lock = self.lock_bus()?;
+        f(self)
+    }
+
+    /// Functionality supported by the adapter
+    ///
+    /// This is queried when the device handle is opened and cached for as
+    /// long as it stays open.
+    pub fn functionality(&self) -> Result<Functionality> {
+        self.with_handle(None, |h| Ok(h.functionality))
+    }
+
+    /// How plain writes and reads are carried out on this adapter
+    pub fn strategy(&self) -> Result<Strategy> {
+        self.with_handle(None, |h| Ok(h.strategy))
+    }
+
+    /// Closes the device handle
+    ///
+    /// The handle is reopened automatically by the next transaction.
+    pub fn close(&self) {
+        *self.lock_handle() = None;
+    }
+
+    /// Closes the device handle, if open, and opens it again immediately
+    pub fn reopen(&self) -> Result<()> {
+        let mut handle = self.lock_handle();
+        *handle = None;
+        *handle = Some(self.open_handle()?);
+        Ok(())
+    }
+
+    fn lock_handle(&self) -> MutexGuard<'_, Option<Handle>> {
+        // A panic while holding the lock can't leave the handle in a state
+        // that is any worse than a failed transaction, so ignore poisoning.
+        self.handle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes the bus lock, if there is one
+    fn lock_bus(&self) -> Result<Option<FileLockGuard<'_>>> {
+        match self.lock {
+            Some(ref lock) => lock.acquire().map(Some).map_err(|e| {
+                I2cError::from(e)
+                    .with_context(Some(&self.path), Some(self.slave), None)
+                    .into()
+            }),
+            None => Ok(None),
+        }
+    }
+
+    fn open_handle(&self) -> Result<Handle> {
+        self.slave.validate()?;
+        let mut i2c = I2c::from_path(&self.path)?;
+        let functionality = i2c.i2c_functionality()?;
+        if self.slave.is_ten_bit() && !functionality.contains(Functionality::TENBIT_ADDR) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} does not support 10-bit addressing", self.path),
+            ));
+        }
+        let strategy = if functionality.contains(Functionality::I2C) {
+            Strategy::RawI2c
+        } else if functionality.intersects(
+            Functionality::SMBUS_READ_BYTE_DATA
+                | Functionality::SMBUS_WRITE_BYTE_DATA
+                | Functionality::SMBUS_READ_WORD_DATA
+                | Functionality::SMBUS_WRITE_WORD_DATA
+                | Functionality::SMBUS_READ_I2C_BLOCK
+                | Functionality::SMBUS_WRITE_I2C_BLOCK,
+        ) {
+            Strategy::SmbusEmulation
+        } else {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "{} supports neither raw I2C nor SMBus data transfers (functionality: {:?})",
+                    self.path, functionality
+                ),
+            ));
+        };
+        i2c.smbus_set_slave_address(self.slave.value(), self.slave.is_ten_bit())?;
+        let kernel_pec = self.pec && functionality.contains(Functionality::SMBUS_PEC);
+        if kernel_pec {
+            i2c.smbus_set_pec(true)?;
+        }
+        Ok(Handle {
+            i2c,
+            address: self.slave,
+            functionality,
+            strategy,
+            pec: self.pec,
+            kernel_pec,
+        })
+    }
+
+    /// Runs `f` against the device handle, opening it first if necessary.
+    /// The bus lock, if any, is held while `f` runs.
+    ///
+    /// The handle is dropped if `f` fails with an error indicating that it
+    /// is no longer usable, so that the next call reopens it. Errors are
+    /// returned as [`I2cError`]s carrying the bus path, slave address and
+    /// `command` byte.
+    fn with_handle<T, F>(&self, command: Option<u8>, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Handle) -> Result<T>,
+    {
+        self.with_raw_handle(f).map_err(|e| {
+            I2cError::from(e)
+                .with_context(Some(&self.path), Some(self.slave), command)
+                .into()
+        })
+    }
+
+    fn with_raw_handle<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Handle) -> Result<T>,
+    {
+        let _lock = self.lock_bus()?;
+        let mut handle = self.lock_handle();
+        if handle.is_none() {
+            *handle = Some(self.open_handle()?);
+        }
+        let result = f(handle.as_mut().unwrap());
+        if let Err(ref e) = result {
+            if is_stale_handle(e) {
+                *handle = None;
+            }
+        }
+        result
+    }
+}
+
+impl SmbusStream for I2CStream {
+    fn quick_command(&self, read: bool) -> Result<()> {
+        let rw = if read {
+            SmbusReadWrite::Read
+        } else {
+            SmbusReadWrite::Write
+        };
+        self.with_handle(None, |h| {
+            h.require(Functionality::SMBUS_QUICK)?;
+            h.i2c.smbus_write_quick(rw)
+        })
+    }
+
+    fn receive_byte(&self) -> Result<u8> {
+        self.smbus(
+            Functionality::SMBUS_READ_BYTE,
+            None,
+            |i2c| i2c.smbus_read_byte(),
+            |s| s.receive_byte(),
+        )
+    }
+
+    fn send_byte(&self, value: u8) -> Result<()> {
+        self.smbus(
+            Functionality::SMBUS_WRITE_BYTE,
+            None,
+            |i2c| i2c.smbus_write_byte(value),
+            |s| s.send_byte(value),
+        )
+    }
+
+    fn read_byte_data(&self, cmd: u8) -> Result<u8> {
+        self.smbus(
+            Functionality::SMBUS_READ_BYTE_DATA,
+            Some(cmd),
+            |i2c| i2c.smbus_read_byte_data(cmd),
+            |s| s.read_byte_data(cmd),
+        )
+    }
+
+    fn write_byte_data(&self, cmd: u8, value: u8) -> Result<()> {
+        self.smbus(
+            Functionality::SMBUS_WRITE_BYTE_DATA,
+            Some(cmd),
+            |i2c| i2c.smbus_write_byte_data(cmd, value),
+            |s| s.write_byte_data(cmd, value),
+        )
+    }
+
+    fn read_word_data(&self, cmd: u8) -> Result<u16> {
+        self.smbus(
+            Functionality::SMBUS_READ_WORD_DATA,
+            Some(cmd),
+            |i2c| i2c.smbus_read_word_data(cmd),
+            |s| s.read_word_data(cmd),
+        )
+    }
+
+    fn write_word_data(&self, cmd: u8, value: u16) -> Result<()> {
+        self.smbus(
+            Functionality::SMBUS_WRITE_WORD_DATA,
+            Some(cmd),
+            |i2c| i2c.smbus_write_word_data(cmd, value),
+            |s| s.write_word_data(cmd, value),
+        )
+    }
+
+    fn process_call(&self, cmd: u8, value: u16) -> Result<u16> {
+        self.smbus(
+            Functionality::SMBUS_PROC_CALL,
+            Some(cmd),
+            |i2c| i2c.smbus_process_call(cmd, value),
+            |s| s.process_call(cmd, value),
+        )
+    }
+
+    fn read_block_data(&self, cmd: u8) -> Result<Vec<u8>> {
+        self.smbus(
+            Functionality::SMBUS_READ_BLOCK_DATA,
+            Some(cmd),
+            |i2c| {
+                let mut data = vec![0; smbus::BLOCK_MAX];
+                let len = i2c.smbus_read_block_data(cmd, &mut data)?;
+                data.truncate(len);
+                Ok(data)
+            },
+            |_| Err(software_pec_unsupported("block read")),
+        )
+    }
+
+    fn write_block_data(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        self.smbus(
+            Functionality::SMBUS_WRITE_BLOCK_DATA,
+            Some(cmd),
+            |i2c| i2c.smbus_write_block_data(cmd, data),
+            |s| s.write_block_data(cmd, data),
+        )
+    }
+
+    fn block_process_call(&self, cmd: u8, data: &[u8]) -> Result<Vec<u8>> {
+        self.smbus(
+            Functionality::SMBUS_BLOCK_PROC_CALL,
+            Some(cmd),
+            |i2c| {
+                let mut read = vec![0; smbus::BLOCK_MAX];
+                let len = i2c.smbus_block_process_call(cmd, data, &mut read)?;
+                read.truncate(len);
+                Ok(read)
+            },
+            |_| Err(software_pec_unsupported("block process call")),
+        )
+    }
+
+    fn exclusive(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        self.sequence(|_| f())
+    }
+}
+
+impl I2CStream {
+    /// Runs a native SMBus transaction, unless PEC is enabled and the kernel
+    /// can't handle it, in which case the transaction is emulated on top of
+    /// this stream's raw transfers so that PEC is done in software.
+    ///
+    /// `needed` is the adapter functionality the native transaction needs
+    /// and `cmd` its command code, if any.
+    fn smbus<T, N, E>(
+        &self,
+        needed: Functionality,
+        cmd: Option<u8>,
+        native: N,
+        emulated: E,
+    ) -> Result<T>
+    where
+        N: FnOnce(&mut I2c<File>) -> Result<T>,
+        E: FnOnce(&Emulated<&Self>) -> Result<T>,
+    {
+        if self.pec && !self.with_handle(cmd, |h| Ok(h.kernel_pec))? {
+            return emulated(&Emulated(self));
         }
+        self.with_handle(cmd, |h| {
+            h.require(needed)?;
+            native(&mut h.i2c)
+        })
     }
 }
 
+/// Error for a variable-length SMBus transaction which would need PEC
+/// support from the kernel
+fn software_pec_unsupported(what: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("adapter does not support PEC on SMBus {}", what),
+    )
+}
+
+/// Whether `err` means the device handle must be reopened
+fn is_stale_handle(err: &Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENODEV) | Some(libc::EBADF))
+}
+
+// Raw transfers carry no command byte of their own: the first bytes written
+// may be a multi-byte register address, or plain data. Errors leave the
+// command out of their context for `Connection` to fill in from the
+// `Command`.
 impl Stream for I2CStream {
     type StreamError = std::io::Error;
 
     /// Writing
     fn write(&self, command: Vec<u8>) -> Result<()> {
-        let mut i2c = I2c::from_path(self.path.clone())?;
-        i2c.smbus_set_slave_address(self.slave, false)?;
-        i2c.i2c_write_block_data(command[0], &command[1..])
+        self.with_handle(None, |h| h.write(&command))
     }
 
     fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
-        let mut i2c = I2c::from_path(self.path.clone())?;
-        i2c.smbus_set_slave_address(self.slave, false)?;
-        i2c.i2c_write_block_data(command[0], &command[1..])
+        self.with_handle(None, |h| h.write(&command))
     }
 
     /// Reading
+    ///
+    /// `command` is written and `rx_len` bytes are read back in a single
+    /// combined transaction, with a repeated start between the two. An empty
+    /// `command` results in a plain read.
     fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
-        let mut i2c = I2c::from_path(self.path.clone())?;
-        i2c.smbus_set_slave_address(self.slave, false)?;
-        let mut data = vec![0; rx_len];
-        let mut msgs = [
-            Message::Read {
-                address: self.slave,
-                data: &mut data,
-                flags: ReadFlags::default()
-            },            
-        ];
-        match i2c.i2c_transfer(&mut msgs) {
-            Ok(_) => Ok(data),
-            Err(e) => Err(e),
-        }  
+        self.with_handle(None, |h| h.read(command, rx_len))
     }
 
     /// Reads command result with Timeout
     fn read_timeout(&self, command: &mut Vec<u8>, rx_len: usize, timeout: Duration) -> Result<Vec<u8>> {
-        let mut i2c = I2c::from_path(self.path.clone())?;
-        i2c.smbus_set_slave_address(self.slave, false)?;
-        i2c.i2c_set_timeout(timeout)?;
-        let mut data = vec![0; rx_len];
-        i2c.i2c_read_block_data(command[0], &mut data)?;
-        Ok(data)
+        self.with_handle(None, |h| {
+            h.i2c.i2c_set_timeout(timeout)?;
+            h.read(command, rx_len)
+        })
     }
 
     /// Read/Write transaction
     fn transfer(&self, command: Vec<u8>, rx_len: usize, delay: Option<Duration>) -> Result<Vec<u8>> {
-        let mut i2c = I2c::from_path(self.path.clone())?;
-        i2c.smbus_set_slave_address(self.slave, false)?;
-        match i2c.i2c_write_block_data(command[0], &command[1..]) {
-            Ok(()) => {
-                thread::sleep(delay.unwrap());
-                let mut data = vec![0; rx_len];
-                let mut msgs = [
-                    Message::Read {
-                        address: self.slave,
-                        data: &mut data,
-                        flags: ReadFlags::default()
-                    },            
-                ];
-                match i2c.i2c_transfer(&mut msgs) {
-                    Ok(_) => Ok(data),
-                    Err(e) => Err(e),
-                } 
-            },
-            Err(e) => Err(e),
-        }        
+        self.with_handle(None, |h| {
+            h.write(&command)?;
+            if let Some(delay) = delay {
+                thread::sleep(delay);
+            }
+            h.read(&[], rx_len)
+        })
     }
 }
 
 /// Struct for abstracting I2C command/data structure
-#[derive(Debug, Eq, PartialEq)]
-pub struct Command {
+///
+/// `cmd` is a single command/register byte by default. Devices with wider
+/// register addresses, or none at all, use a [`RegisterAddress`] instead,
+/// which is written ahead of the data in its own byte order:
+///
+/// ```
+/// use i2c_rs::{Command, Endian, RegisterAddress};
+///
+/// let command = Command {
+///     cmd: RegisterAddress::u16(0x1F40, Endian::Big),
+///     data: vec![0xAA],
+/// };
+/// ```
+///
+/// The address bytes are sent as part of a plain I2C write, or of the
+/// write half of a combined write-then-read, not as an SMBus command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Command<A = u8> {
     /// I2C command or registry
-    pub cmd: u8,
+    pub cmd: A,
     /// Data to write to registry
     pub data: Vec<u8>,
 }
 
+impl<A: Into<RegisterAddress> + Copy> Command<A> {
+    /// Register address followed by the data, as put on the wire
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.cmd.into().to_bytes();
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    /// Command byte for errors and tracing, if the register address is a
+    /// single byte
+    fn command_byte(&self) -> Option<u8> {
+        self.cmd.into().command_byte()
+    }
+}
+
 /// Struct for communicating with an I2C device
+///
+/// Failed transactions are retried according to the connection's
+/// [`RetryPolicy`], which by default never retries.
+///
+/// With the `log` feature enabled, every transaction attempt is logged
+/// with its bus path, slave address, command byte, the bytes written and
+/// read, its duration and its outcome. Transactions are logged at `Debug`
+/// level unless `Connection::with_trace_level` says otherwise.
 pub struct Connection {
-    stream: Box<dyn Stream<StreamError = std::io::Error> + Send>,
+    stream: Box<dyn SmbusStream + Send>,
+    retry: RetryPolicy,
+    path: Option<String>,
+    address: Option<Address>,
+    #[cfg(feature = "log")]
+    trace_level: Option<log::Level>,
 }
 
 impl Connection {
     /// I2C connection constructor
     ///
+    /// SMBus transactions are emulated on top of the stream's raw transfers.
+    /// A plain `Stream` has no way to hold a bus lock across several
+    /// transactions, so [`Connection::exclusive`] does not lock the bus on
+    /// such a connection, even if `stream` is an [`I2CStream`] with a bus
+    /// lock file. Use [`Connection::from_stream`] for those.
+    ///
     /// # Arguments
     ///
-    /// `path` - Path to I2C device
-    /// `slave` - I2C slave address to read/write to
+    /// `stream` - Stream to communicate through
     pub fn new(stream: Box<dyn Stream<StreamError = std::io::Error> + Send>) -> Self {
-        Self { stream }
+        Self::with_smbus_stream(Box::new(Emulated(stream)))
     }
 
-    /// Convenience constructor for creating a Connection with an I2CStream.
+    /// I2C connection constructor for streams with their own SMBus support
     ///
     /// # Arguments
     ///
-    /// `path` - Path to I2C device
-    /// `slave` - I2C slave address
-    pub fn from_path(path: &str, slave: u16) -> Self {
+    /// `stream` - Stream to communicate through
+    pub fn with_smbus_stream(stream: Box<dyn SmbusStream + Send>) -> Self {
         Self {
-            stream: Box::new(I2CStream::new(path, slave)),
+            stream,
+            retry: RetryPolicy::default(),
+            path: None,
+            address: None,
+            #[cfg(feature = "log")]
+            trace_level: Some(log::Level::Debug),
         }
     }
 
+    /// Convenience constructor for creating a Connection with an I2CStream.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path to I2C device
+    /// `slave` - I2C slave address, either a plain 7-bit address or an
+    ///           [`Address`]
+    pub fn from_path(path: &str, slave: impl Into<Address>) -> Self {
+        Self::from_stream(I2CStream::new(path, slave))
+    }
+
+    /// Creates a Connection with an existing I2CStream, keeping its native
+    /// SMBus transactions and its bus lock, if any
+    ///
+    /// # Arguments
+    ///
+    /// `stream` - Stream to communicate through
+    pub fn from_stream(stream: I2CStream) -> Self {
+        let path = stream.path.clone();
+        let slave = stream.slave;
+        Self::with_smbus_stream(Box::new(stream)).located(&path, slave)
+    }
+
+    /// Records which bus and slave the connection talks to, for errors and
+    /// tracing
+    pub(crate) fn located(mut self, path: &str, address: Address) -> Self {
+        self.path = Some(path.to_string());
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the level at which this connection's transactions are logged
+    ///
+    /// # Arguments
+    ///
+    /// `level` - Log level, or `None` to not log transactions at all
+    #[cfg(feature = "log")]
+    pub fn with_trace_level(mut self, level: Option<log::Level>) -> Self {
+        self.trace_level = level;
+        self
+    }
+
+    /// Sets the retry policy for all transactions on this connection
+    ///
+    /// # Arguments
+    ///
+    /// `policy` - Retry policy
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Retry policy for transactions on this connection
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
     /// Writes an I2C command
     ///
     /// # Arguments
     ///
     /// `command` - Command to write
-    pub fn write(&self, command: Command) -> Result<()> {
-        let mut buf = command.data;
-        buf.insert(0,command.cmd);
-        self.stream.write(buf)
+    pub fn write<A: Into<RegisterAddress> + Copy>(&self, command: Command<A>) -> I2cResult<()> {
+        self.write_with(command, &self.retry).map(|r| r.value)
+    }
+
+    /// Writes an I2C command, retrying according to `policy`
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to write
+    /// `policy` - Retry policy to use instead of the connection's
+    pub fn write_with<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        policy: &RetryPolicy,
+    ) -> I2cResult<Retried<()>> {
+        let buf = command.to_bytes();
+        let transaction = Transaction::new("write", command.command_byte(), &buf);
+        self.call(policy, &transaction, |s| s.write(buf.clone()))
     }
 
     /// Reads command result
@@ -175,11 +931,32 @@ impl Connection {
     ///
     /// `command` - Command to read result from
     /// `rx_len`  - Amount of data to read
-    pub fn read(&self, command: Command, rx_len: usize) -> Result<Vec<u8>> {
-        let mut buf = command.data;
-        buf.insert(0,command.cmd);
-        self.stream.read(&mut buf,rx_len)
-    }    
+    pub fn read<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+    ) -> I2cResult<Vec<u8>> {
+        self.read_with(command, rx_len, &self.retry)
+            .map(|r| r.value)
+    }
+
+    /// Reads command result, retrying according to `policy`
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to read result from
+    /// `rx_len`  - Amount of data to read
+    /// `policy` - Retry policy to use instead of the connection's
+    pub fn read_with<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+        policy: &RetryPolicy,
+    ) -> I2cResult<Retried<Vec<u8>>> {
+        let buf = command.to_bytes();
+        let transaction = Transaction::new("read", command.command_byte(), &buf);
+        self.call(policy, &transaction, |s| s.read(&mut buf.clone(), rx_len))
+    }
 
     /// Writes I2C command and reads result
     ///
@@ -188,9 +965,679 @@ impl Connection {
     /// `command` - Command to write and read from
     /// `rx_len`  - Amount of data to read
     /// `delay` - Delay between writing and reading
-    pub fn transfer(&self, command: Command, rx_len: usize, delay: Duration) -> Result<Vec<u8>> {
-        let mut buf = command.data;
-        buf.insert(0,command.cmd);
-        self.stream.transfer(buf, rx_len, Some(delay))
+    pub fn transfer<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+        delay: Duration,
+    ) -> I2cResult<Vec<u8>> {
+        self.transfer_with(command, rx_len, delay, &self.retry)
+            .map(|r| r.value)
+    }
+
+    /// Writes I2C command and reads result, retrying according to `policy`
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Command to write and read from
+    /// `rx_len`  - Amount of data to read
+    /// `delay` - Delay between writing and reading
+    /// `policy` - Retry policy to use instead of the connection's
+    pub fn transfer_with<A: Into<RegisterAddress> + Copy>(
+        &self,
+        command: Command<A>,
+        rx_len: usize,
+        delay: Duration,
+        policy: &RetryPolicy,
+    ) -> I2cResult<Retried<Vec<u8>>> {
+        let buf = command.to_bytes();
+        let transaction = Transaction::new("transfer", command.command_byte(), &buf);
+        self.call(policy, &transaction, |s| {
+            s.transfer(buf.clone(), rx_len, Some(delay))
+        })
+    }
+
+    /// Performs an SMBus Quick Command
+    ///
+    /// # Arguments
+    ///
+    /// `read` - Value of the R/W bit
+    pub fn quick_command(&self, read: bool) -> I2cResult<()> {
+        self.smbus(Transaction::new("quick_command", None, &[]), |s| {
+            s.quick_command(read)
+        })
+    }
+
+    /// Performs an SMBus Receive Byte
+    pub fn receive_byte(&self) -> I2cResult<u8> {
+        self.smbus(Transaction::new("receive_byte", None, &[]), |s| {
+            s.receive_byte()
+        })
+    }
+
+    /// Performs an SMBus Send Byte
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Byte to send
+    pub fn send_byte(&self, value: u8) -> I2cResult<()> {
+        self.smbus(Transaction::new("send_byte", None, &[value]), |s| {
+            s.send_byte(value)
+        })
+    }
+
+    /// Performs an SMBus Read Byte
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    pub fn read_byte_data(&self, cmd: u8) -> I2cResult<u8> {
+        self.smbus(Transaction::new("read_byte_data", Some(cmd), &[cmd]), |s| {
+            s.read_byte_data(cmd)
+        })
+    }
+
+    /// Performs an SMBus Write Byte
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Byte to write
+    pub fn write_byte_data(&self, cmd: u8, value: u8) -> I2cResult<()> {
+        self.smbus(
+            Transaction::new("write_byte_data", Some(cmd), &[cmd, value]),
+            |s| s.write_byte_data(cmd, value),
+        )
+    }
+
+    /// Performs an SMBus Read Word
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    pub fn read_word_data(&self, cmd: u8) -> I2cResult<u16> {
+        self.smbus(Transaction::new("read_word_data", Some(cmd), &[cmd]), |s| {
+            s.read_word_data(cmd)
+        })
+    }
+
+    /// Performs an SMBus Write Word
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Word to write
+    pub fn write_word_data(&self, cmd: u8, value: u16) -> I2cResult<()> {
+        let [lo, hi] = value.to_le_bytes();
+        self.smbus(
+            Transaction::new("write_word_data", Some(cmd), &[cmd, lo, hi]),
+            |s| s.write_word_data(cmd, value),
+        )
+    }
+
+    /// Performs an SMBus Process Call
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Word to write
+    pub fn process_call(&self, cmd: u8, value: u16) -> I2cResult<u16> {
+        let [lo, hi] = value.to_le_bytes();
+        self.smbus(
+            Transaction::new("process_call", Some(cmd), &[cmd, lo, hi]),
+            |s| s.process_call(cmd, value),
+        )
+    }
+
+    /// Performs an SMBus Block Read
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    pub fn read_block_data(&self, cmd: u8) -> I2cResult<Vec<u8>> {
+        self.smbus(
+            Transaction::new("read_block_data", Some(cmd), &[cmd]),
+            |s| s.read_block_data(cmd),
+        )
+    }
+
+    /// Performs an SMBus Block Write
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `data` - Up to 32 bytes to write
+    pub fn write_block_data(&self, cmd: u8, data: &[u8]) -> I2cResult<()> {
+        let tx = block_bytes(cmd, data);
+        self.smbus(Transaction::new("write_block_data", Some(cmd), &tx), |s| {
+            s.write_block_data(cmd, data)
+        })
+    }
+
+    /// Performs an SMBus Block Write-Block Read Process Call
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `data` - Up to 32 bytes to write
+    pub fn block_process_call(&self, cmd: u8, data: &[u8]) -> I2cResult<Vec<u8>> {
+        let tx = block_bytes(cmd, data);
+        self.smbus(
+            Transaction::new("block_process_call", Some(cmd), &tx),
+            |s| s.block_process_call(cmd, data),
+        )
+    }
+
+    /// Reads a typed register value
+    ///
+    /// # Arguments
+    ///
+    /// `reg` - Register to read, either a plain byte or a
+    ///         [`RegisterAddress`]
+    /// `endian` - Byte order of the value
+    pub fn read_register<T: RegisterValue>(
+        &self,
+        reg: impl Into<RegisterAddress>,
+        endian: Endian,
+    ) -> I2cResult<T> {
+        self.read_registers(reg, 1, endian).map(|values| values[0])
+    }
+
+    /// Reads consecutive typed register values in a single burst, relying
+    /// on the slave to advance its register pointer
+    ///
+    /// # Arguments
+    ///
+    /// `reg` - First register to read, either a plain byte or a
+    ///         [`RegisterAddress`]
+    /// `count` - Number of values to read
+    /// `endian` - Byte order of the values
+    pub fn read_registers<T: RegisterValue>(
+        &self,
+        reg: impl Into<RegisterAddress>,
+        count: usize,
+        endian: Endian,
+    ) -> I2cResult<Vec<T>> {
+        let reg = reg.into();
+        let data = self.read(
+            Command {
+                cmd: reg,
+                data: vec![],
+            },
+            T::SIZE * count,
+        )?;
+        register::decode(&data, count, endian).map_err(|msg| {
+            I2cError::new(I2cErrorKind::Parse, &msg).with_context(
+                self.path.as_deref(),
+                self.address,
+                reg.command_byte(),
+            )
+        })
+    }
+
+    /// Writes a typed register value
+    ///
+    /// # Arguments
+    ///
+    /// `reg` - Register to write, either a plain byte or a
+    ///         [`RegisterAddress`]
+    /// `value` - Value to write
+    /// `endian` - Byte order of the value
+    pub fn write_register<T: RegisterValue>(
+        &self,
+        reg: impl Into<RegisterAddress>,
+        value: T,
+        endian: Endian,
+    ) -> I2cResult<()> {
+        self.write_registers(reg, &[value], endian)
+    }
+
+    /// Writes consecutive typed register values in a single burst, relying
+    /// on the slave to advance its register pointer
+    ///
+    /// # Arguments
+    ///
+    /// `reg` - First register to write, either a plain byte or a
+    ///         [`RegisterAddress`]
+    /// `values` - Values to write
+    /// `endian` - Byte order of the values
+    pub fn write_registers<T: RegisterValue>(
+        &self,
+        reg: impl Into<RegisterAddress>,
+        values: &[T],
+        endian: Endian,
+    ) -> I2cResult<()> {
+        let data = values.iter().flat_map(|v| v.to_bytes(endian)).collect();
+        self.write(Command {
+            cmd: reg.into(),
+            data,
+        })
+    }
+
+    /// Reads a register, changes the bits selected by `mask` to those of
+    /// `value` and writes it back under [`Connection::exclusive`]
+    ///
+    /// The write is skipped if the bits already have the wanted value.
+    /// Returns whether the register was written.
+    ///
+    /// The read-modify-write is only atomic if [`Connection::exclusive`]
+    /// locks the bus, i.e. for connections from a [`SharedBus`] and for
+    /// connections to an [`I2CStream`] with a bus lock file. Otherwise
+    /// another user of the bus may change the register in between.
+    ///
+    /// # Arguments
+    ///
+    /// `reg` - Register to update
+    /// `mask` - Bits to change
+    /// `value` - New value of the bits
+    pub fn update_bits(&self, reg: u8, mask: u8, value: u8) -> I2cResult<bool> {
+        self.update_register(reg, mask, value, Endian::Big, false)
+    }
+
+    /// Typed [`Connection::update_bits`], optionally reading the register
+    /// back to verify the write
+    ///
+    /// # Arguments
+    ///
+    /// `reg` - Register to update, either a plain byte or a
+    ///         [`RegisterAddress`]
+    /// `mask` - Bits to change
+    /// `value` - New value of the bits
+    /// `endian` - Byte order of the register value
+    /// `verify` - Whether to read the register back after writing it
+    pub fn update_register<T>(
+        &self,
+        reg: impl Into<RegisterAddress>,
+        mask: T,
+        value: T,
+        endian: Endian,
+        verify: bool,
+    ) -> I2cResult<bool>
+    where
+        T: RegisterValue
+            + PartialEq
+            + fmt::LowerHex
+            + BitAnd<Output = T>
+            + BitOr<Output = T>
+            + Not<Output = T>,
+    {
+        let reg = reg.into();
+        self.exclusive(|c| {
+            let old: T = c.read_register(reg, endian)?;
+            let new = (old & !mask) | (value & mask);
+            if new == old {
+                return Ok(false);
+            }
+            c.write_register(reg, new, endian)?;
+            if verify {
+                let read_back: T = c.read_register(reg, endian)?;
+                if read_back & mask != new & mask {
+                    let msg = format!("wrote {:#x} but read back {:#x}", new, read_back);
+                    return Err(
+                        I2cError::new(I2cErrorKind::VerifyMismatch, &msg).with_context(
+                            c.path.as_deref(),
+                            c.address,
+                            reg.command_byte(),
+                        ),
+                    );
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// Runs `f` with the bus locked, so that the transactions it makes on
+    /// this connection can't be interleaved with other users of the bus
+    ///
+    /// The bus is locked only if the connection's stream provides a lock
+    /// through [`SmbusStream::exclusive`]: connections from a [`SharedBus`],
+    /// and connections made with [`Connection::from_stream`] or
+    /// [`Connection::with_smbus_stream`] from an [`I2CStream`] with a bus
+    /// lock file, or a [`Recorder`] of one. Otherwise, and in particular for
+    /// any connection made with [`Connection::new`], `f` just runs and
+    /// other users of the bus may slip in between its transactions.
+    ///
+    /// # Arguments
+    ///
+    /// `f` - Sequence of transactions to run
+    pub fn exclusive<T, F>(&self, f: F) -> I2cResult<T>
+    where
+        F: FnOnce(&Self) -> I2cResult<T>,
+    {
+        let mut f = Some(f);
+        let mut result = None;
+        self.stream
+            .exclusive(&mut || {
+                if let Some(f) = f.take() {
+                    result = Some(f(self));
+                }
+                Ok(())
+            })
+            .map_err(|e| {
+                I2cError::from(e).with_context(self.path.as_deref(), self.address, None)
+            })?;
+        result.expect("stream did not run the exclusive sequence")
+    }
+
+    /// Reads a [`bitfield!`] register
+    pub fn read_bitfield<R: Bitfield>(&self) -> I2cResult<R> {
+        let value = self.read_register::<R::Value>(R::ADDRESS, R::ENDIAN)?;
+        R::from_value(value).ok_or_else(|| {
+            I2cError::new(
+                I2cErrorKind::Parse,
+                &format!("invalid field value in register value {:#x}", value),
+            )
+            .with_context(self.path.as_deref(), self.address, Some(R::ADDRESS))
+        })
+    }
+
+    /// Writes a [`bitfield!`] register
+    ///
+    /// # Arguments
+    ///
+    /// `register` - Register value to write
+    pub fn write_bitfield<R: Bitfield>(&self, register: R) -> I2cResult<()> {
+        self.write_register(R::ADDRESS, register.value(), R::ENDIAN)
+    }
+
+    /// Reads a [`bitfield!`] register, applies `f` and writes the result
+    /// back under [`Connection::exclusive`], returning the new value
+    ///
+    /// # Arguments
+    ///
+    /// `f` - Function modifying the register's fields
+    pub fn modify_bitfield<R, F>(&self, f: F) -> I2cResult<R>
+    where
+        R: Bitfield,
+        F: FnOnce(&mut R),
+    {
+        self.exclusive(|c| {
+            let mut register = c.read_bitfield()?;
+            f(&mut register);
+            c.write_bitfield(register)?;
+            Ok(register)
+        })
+    }
+
+    /// Runs an SMBus transaction under the connection's retry policy
+    fn smbus<T, F>(&self, transaction: Transaction<'_>, f: F) -> I2cResult<T>
+    where
+        T: Response,
+        F: FnMut(&dyn SmbusStream) -> Result<T>,
+    {
+        self.call(&self.retry, &transaction, f).map(|r| r.value)
+    }
+
+    /// Runs a transaction on the stream under `policy`, filling in the
+    /// connection's path and address and the transaction's command byte in
+    /// errors unless the stream already did
+    fn call<T, F>(
+        &self,
+        policy: &RetryPolicy,
+        transaction: &Transaction<'_>,
+        mut f: F,
+    ) -> I2cResult<Retried<T>>
+    where
+        T: Response,
+        F: FnMut(&dyn SmbusStream) -> Result<T>,
+    {
+        policy.run(|| {
+            let start = Instant::now();
+            let result = f(&*self.stream).map_err(|e| {
+                I2cError::from(e).with_context(self.path.as_deref(), self.address, transaction.cmd)
+            });
+            self.trace(transaction, &result, start.elapsed());
+            result
+        })
+    }
+
+    #[cfg(feature = "log")]
+    fn trace<T: Response>(
+        &self,
+        transaction: &Transaction<'_>,
+        result: &I2cResult<T>,
+        duration: Duration,
+    ) {
+        log_transaction(
+            self.trace_level,
+            self.path.as_deref(),
+            self.address,
+            transaction,
+            result,
+            duration,
+        );
+    }
+
+    #[cfg(not(feature = "log"))]
+    fn trace<T>(
+        &self,
+        _transaction: &Transaction<'_>,
+        _result: &I2cResult<T>,
+        _duration: Duration,
+    ) {
+    }
+}
+
+/// Logs a transaction attempt at `level`, unless it is `None`
+#[cfg(feature = "log")]
+pub(crate) fn log_transaction<T: Response>(
+    level: Option<log::Level>,
+    path: Option<&str>,
+    address: Option<Address>,
+    transaction: &Transaction<'_>,
+    result: &I2cResult<T>,
+    duration: Duration,
+) {
+    let level = match level {
+        Some(level) if log::log_enabled!(level) => level,
+        _ => return,
+    };
+    let path = path.unwrap_or("-");
+    let address = address.map_or_else(|| "-".to_string(), |a| a.to_string());
+    let cmd = transaction
+        .cmd
+        .map_or_else(|| "-".to_string(), |c| format!("{:#04x}", c));
+    match result {
+        Ok(response) => log::log!(
+            level,
+            "bus={} addr={} op={} cmd={} tx={} rx={} duration={:?} outcome=ok",
+            path,
+            address,
+            transaction.op,
+            cmd,
+            record::hex(transaction.tx),
+            record::hex(&response.bytes()),
+            duration
+        ),
+        Err(e) => log::log!(
+            level,
+            "bus={} addr={} op={} cmd={} tx={} duration={:?} outcome={} error=\"{}\"",
+            path,
+            address,
+            transaction.op,
+            cmd,
+            record::hex(transaction.tx),
+            duration,
+            e.kind(),
+            e
+        ),
+    }
+}
+
+/// Description of a transaction, for errors and tracing
+#[cfg_attr(not(feature = "log"), allow(dead_code))]
+pub(crate) struct Transaction<'a> {
+    /// Name of the operation
+    op: &'static str,
+    /// Command/register byte
+    cmd: Option<u8>,
+    /// Bytes written
+    tx: &'a [u8],
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(op: &'static str, cmd: Option<u8>, tx: &'a [u8]) -> Self {
+        Self { op, cmd, tx }
+    }
+}
+
+/// Bytes of an SMBus block write as they go on the wire: command, byte count
+/// and data
+pub(crate) fn block_bytes(cmd: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + 2);
+    bytes.push(cmd);
+    bytes.push(data.len() as u8);
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// Value returned by a transaction, as it was read off the bus
+#[cfg_attr(not(all(feature = "log", feature = "tokio")), allow(dead_code))]
+pub(crate) trait Response {
+    /// Bytes read
+    fn bytes(&self) -> Vec<u8>;
+
+    /// Value read as `bytes`
+    fn from_bytes(bytes: Vec<u8>) -> Self;
+}
+
+impl Response for () {
+    fn bytes(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn from_bytes(_bytes: Vec<u8>) -> Self {}
+}
+
+impl Response for u8 {
+    fn bytes(&self) -> Vec<u8> {
+        vec![*self]
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        bytes[0]
+    }
+}
+
+impl Response for u16 {
+    fn bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+}
+
+impl Response for Vec<u8> {
+    fn bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(mock: &MockStream) -> Connection {
+        Connection::new(Box::new(mock.clone()))
+    }
+
+    #[test]
+    fn update_bits_writes_changed_register() {
+        let mock = MockStream::new();
+        mock.expect_read(vec![0x20], 1, Ok(vec![0b1010_0101]));
+        mock.expect_write(vec![0x20, 0b1010_1001], Ok(()));
+        assert!(connection(&mock)
+            .update_bits(0x20, 0b0000_1100, 0b0000_1000)
+            .unwrap());
+        mock.verify();
+    }
+
+    #[test]
+    fn update_bits_skips_unchanged_register() {
+        let mock = MockStream::new();
+        mock.expect_read(vec![0x20], 1, Ok(vec![0b1010_0101]));
+        assert!(!connection(&mock)
+            .update_bits(0x20, 0b0000_0110, 0b0000_0100)
+            .unwrap());
+        mock.verify();
+    }
+
+    #[test]
+    fn update_register_verifies_write() {
+        let mock = MockStream::new();
+        mock.expect_read(vec![0x30], 2, Ok(vec![0x12, 0x34]));
+        mock.expect_write(vec![0x30, 0x12, 0xF4], Ok(()));
+        mock.expect_read(vec![0x30], 2, Ok(vec![0x12, 0xF4]));
+        let written = connection(&mock)
+            .update_register(0x30, 0x00F0u16, 0x00F0, Endian::Big, true)
+            .unwrap();
+        assert!(written);
+        mock.verify();
+    }
+
+    #[test]
+    fn update_register_read_back_mismatch() {
+        let mock = MockStream::new();
+        mock.expect_read(vec![0x30], 2, Ok(vec![0x34, 0x12]));
+        mock.expect_write(vec![0x30, 0xF4, 0x12], Ok(()));
+        mock.expect_read(vec![0x30], 2, Ok(vec![0x34, 0x12]));
+        let err = connection(&mock)
+            .update_register(0x30, 0x00F0u16, 0x00F0, Endian::Little, true)
+            .unwrap_err();
+        assert_eq!(err.kind(), I2cErrorKind::VerifyMismatch);
+        assert_eq!(err.context().command, Some(0x30));
+        assert_eq!(err.message(), "wrote 0x12f4 but read back 0x1234");
+        mock.verify();
+    }
+
+    #[test]
+    fn wide_register_addresses_on_the_wire() {
+        let mock = MockStream::new();
+        mock.expect_read(vec![0x40, 0x1F], 2, Ok(vec![0x12, 0x34]));
+        mock.expect_read(vec![0x1F, 0x40], 1, Ok(vec![0x56]));
+        mock.expect_write(vec![0x04, 0x03, 0x02, 0x01, 0xAA], Ok(()));
+        mock.expect_write(vec![0x01, 0x02, 0x03, 0x04, 0xAA, 0xBB], Ok(()));
+        let connection = connection(&mock);
+
+        let value: u16 = connection
+            .read_register(RegisterAddress::u16(0x1F40, Endian::Little), Endian::Big)
+            .unwrap();
+        assert_eq!(value, 0x1234);
+        let value: u8 = connection
+            .read_register(RegisterAddress::u16(0x1F40, Endian::Big), Endian::Big)
+            .unwrap();
+        assert_eq!(value, 0x56);
+        connection
+            .write_register(
+                RegisterAddress::u32(0x0102_0304, Endian::Little),
+                0xAAu8,
+                Endian::Big,
+            )
+            .unwrap();
+        connection
+            .write_register(
+                RegisterAddress::u32(0x0102_0304, Endian::Big),
+                0xAABBu16,
+                Endian::Big,
+            )
+            .unwrap();
+        mock.verify();
+    }
+
+    #[test]
+    fn register_address_none_reads_plainly() {
+        let mock = MockStream::new();
+        mock.expect_read(vec![], 1, Ok(vec![0x7F]));
+        let value: u8 = connection(&mock)
+            .read_register(RegisterAddress::NONE, Endian::Big)
+            .unwrap();
+        assert_eq!(value, 0x7F);
+        mock.verify();
     }
 }
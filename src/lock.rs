@@ -0,0 +1,297 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Bus locks shared between threads and processes
+
+use crate::{I2cError, I2cErrorKind};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// How long to wait between attempts to take a contended lock file
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A re-entrant lock, held by at most one thread at a time
+#[derive(Default)]
+pub(crate) struct ReentrantLock {
+    owner: Mutex<Owner>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct Owner {
+    thread: Option<ThreadId>,
+    depth: usize,
+}
+
+impl ReentrantLock {
+    pub(crate) fn acquire(&self) -> ReentrantGuard<'_> {
+        let me = thread::current().id();
+        let mut owner = self.owner();
+        while owner.held_by_other(me) {
+            owner = self.released.wait(owner).unwrap_or_else(|e| e.into_inner());
+        }
+        self.enter(owner, me)
+    }
+
+    /// Like [`ReentrantLock::acquire`], but gives up once `deadline` has
+    /// passed
+    pub(crate) fn acquire_until(&self, deadline: Instant) -> Option<ReentrantGuard<'_>> {
+        let me = thread::current().id();
+        let mut owner = self.owner();
+        while owner.held_by_other(me) {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            owner = self
+                .released
+                .wait_timeout(owner, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        Some(self.enter(owner, me))
+    }
+
+    fn enter(&self, mut owner: MutexGuard<'_, Owner>, me: ThreadId) -> ReentrantGuard<'_> {
+        owner.thread = Some(me);
+        owner.depth += 1;
+        ReentrantGuard {
+            lock: self,
+            outermost: owner.depth == 1,
+        }
+    }
+
+    fn owner(&self) -> MutexGuard<'_, Owner> {
+        self.owner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Owner {
+    fn held_by_other(&self, me: ThreadId) -> bool {
+        self.thread.is_some() && self.thread != Some(me)
+    }
+}
+
+pub(crate) struct ReentrantGuard<'a> {
+    lock: &'a ReentrantLock,
+    outermost: bool,
+}
+
+impl Drop for ReentrantGuard<'_> {
+    fn drop(&mut self) {
+        let mut owner = self.lock.owner();
+        owner.depth -= 1;
+        if owner.depth == 0 {
+            owner.thread = None;
+            self.lock.released.notify_one();
+        }
+    }
+}
+
+/// An advisory `flock` on a lock file, shared by everything that uses the
+/// same lock file for the same bus
+///
+/// The lock is re-entrant within a thread, so a sequence of transactions
+/// can hold it while each transaction takes it again. Other threads using
+/// the same `FileLock` wait for it in-process, within the same timeout as
+/// other processes.
+pub(crate) struct FileLock {
+    path: String,
+    timeout: Duration,
+    local: ReentrantLock,
+    file: Mutex<Option<File>>,
+}
+
+impl FileLock {
+    pub(crate) fn new(path: &str, timeout: Duration) -> Self {
+        Self {
+            path: path.to_string(),
+            timeout,
+            local: ReentrantLock::default(),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Takes the lock, waiting up to the timeout for other threads and
+    /// processes to release it
+    pub(crate) fn acquire(&self) -> Result<FileLockGuard<'_>> {
+        let deadline = Instant::now() + self.timeout;
+        let guard = match self.local.acquire_until(deadline) {
+            Some(guard) => guard,
+            None => return Err(self.timed_out()),
+        };
+        if guard.outermost {
+            *self.file() = Some(self.lock_file(deadline)?);
+        }
+        Ok(FileLockGuard {
+            lock: self,
+            local: guard,
+        })
+    }
+
+    fn lock_file(&self, deadline: Instant) -> Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o666)
+            .open(&self.path)?;
+        loop {
+            // SAFETY: `file` owns a valid open file descriptor
+            let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+            if ret == 0 {
+                return Ok(file);
+            }
+            let err = Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => {}
+                Some(libc::EINTR) => continue,
+                _ => return Err(err),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(self.timed_out());
+            }
+            thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    fn timed_out(&self) -> Error {
+        I2cError::new(I2cErrorKind::LockTimeout, &self.path).into()
+    }
+
+    fn file(&self) -> MutexGuard<'_, Option<File>> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub(crate) struct FileLockGuard<'a> {
+    lock: &'a FileLock,
+    local: ReentrantGuard<'a>,
+}
+
+impl Drop for FileLockGuard<'_> {
+    fn drop(&mut self) {
+        if self.local.outermost {
+            // Closing the lock file releases the lock
+            *self.lock.file() = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process;
+
+    const SHORT: Duration = Duration::from_millis(20);
+
+    fn lock_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("i2c-rs-{}-{}.lock", name, process::id()))
+    }
+
+    #[test]
+    fn reentrant_depth() {
+        let lock = ReentrantLock::default();
+        let outer = lock.acquire();
+        let inner = lock.acquire_until(Instant::now()).unwrap();
+        assert!(outer.outermost);
+        assert!(!inner.outermost);
+        assert_eq!(lock.owner().depth, 2);
+
+        drop(inner);
+        assert_eq!(lock.owner().depth, 1);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| lock.acquire_until(Instant::now() + SHORT).is_none());
+            assert!(waiter.join().unwrap());
+        });
+
+        drop(outer);
+        assert_eq!(lock.owner().depth, 0);
+        assert!(lock.owner().thread.is_none());
+        thread::scope(|s| {
+            let waiter = s.spawn(|| lock.acquire_until(Instant::now() + SHORT).is_some());
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn reentrant_waiter_gets_released_lock() {
+        let lock = ReentrantLock::default();
+        let guard = lock.acquire();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let guard = lock.acquire_until(Instant::now() + Duration::from_secs(10));
+                guard.map(|guard| guard.outermost)
+            });
+            thread::sleep(SHORT);
+            drop(guard);
+            assert_eq!(waiter.join().unwrap(), Some(true));
+        });
+    }
+
+    #[test]
+    fn file_lock_reentrant() {
+        let path = lock_path("reentrant");
+        let lock = FileLock::new(path.to_str().unwrap(), SHORT);
+        {
+            let _outer = lock.acquire().unwrap();
+            let _inner = lock.acquire().unwrap();
+            assert!(lock.file().is_some());
+        }
+        assert!(lock.file().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_lock_timeout_between_threads() {
+        let path = lock_path("threads");
+        let lock = FileLock::new(path.to_str().unwrap(), SHORT);
+        let _guard = lock.acquire().unwrap();
+        thread::scope(|s| {
+            let err = s.spawn(|| lock.acquire().err()).join().unwrap().unwrap();
+            assert_eq!(I2cErrorKind::of(&err), I2cErrorKind::LockTimeout);
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_lock_timeout_between_locks() {
+        // Each FileLock opens the file separately, so their flocks conflict
+        // just as they would between processes
+        let path = lock_path("files");
+        let first = FileLock::new(path.to_str().unwrap(), SHORT);
+        let second = FileLock::new(path.to_str().unwrap(), SHORT);
+
+        let guard = first.acquire().unwrap();
+        let start = Instant::now();
+        let err = second.acquire().err().unwrap();
+        assert_eq!(I2cErrorKind::of(&err), I2cErrorKind::LockTimeout);
+        assert!(start.elapsed() >= SHORT);
+        assert!(err.to_string().contains(path.to_str().unwrap()));
+
+        drop(guard);
+        assert!(second.acquire().is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Scriptable mock stream for testing device drivers
+//!
+//! ```
+//! use i2c_rs::{Command, Connection, MockStream};
+//!
+//! let mock = MockStream::new();
+//! mock.expect_read(vec![0x10], 2, Ok(vec![0x12, 0x34]));
+//!
+//! let connection = Connection::new(Box::new(mock.clone()));
+//! let data = connection.read(Command { cmd: 0x10, data: vec![] }, 2).unwrap();
+//! assert_eq!(data, vec![0x12, 0x34]);
+//! ```
+
+use hal_stream::Stream;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Result;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// A `Stream` which checks its calls against a queue of expectations and
+/// answers them with canned responses
+///
+/// Expected calls must arrive in the order they were queued. An unexpected
+/// or out-of-order call panics, as does dropping the mock while expectations
+/// are left. Clones share the same queue, so a clone can be handed to a
+/// `Connection` while the test keeps the original to queue more
+/// expectations or [`verify`](MockStream::verify) them. The drop check runs
+/// once the last clone is dropped.
+///
+/// `write_bytes` is treated as `write` and `read_timeout` as `read`. The
+/// delay passed to `transfer` is ignored.
+#[derive(Clone, Default)]
+pub struct MockStream {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    expectations: VecDeque<Expectation>,
+}
+
+struct Expectation {
+    call: Call,
+    response: Result<Vec<u8>>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Call {
+    Write(Vec<u8>),
+    Read { command: Vec<u8>, rx_len: usize },
+    Transfer { command: Vec<u8>, rx_len: usize },
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Call::Write(data) => write!(f, "write({:02x?})", data),
+            Call::Read { command, rx_len } => write!(f, "read({:02x?}, {})", command, rx_len),
+            Call::Transfer { command, rx_len } => {
+                write!(f, "transfer({:02x?}, {})", command, rx_len)
+            }
+        }
+    }
+}
+
+impl MockStream {
+    /// Creates a mock without any expectations
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects a write
+    ///
+    /// # Arguments
+    ///
+    /// `data` - Bytes the write must carry, including the command byte
+    /// `response` - Result to return
+    pub fn expect_write(&self, data: Vec<u8>, response: Result<()>) -> &Self {
+        self.expect(Call::Write(data), response.map(|()| Vec::new()))
+    }
+
+    /// Expects a combined write and read
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Bytes written before reading
+    /// `rx_len` - Number of bytes the read must ask for
+    /// `response` - Bytes read, or error to return
+    pub fn expect_read(&self, command: Vec<u8>, rx_len: usize, response: Result<Vec<u8>>) -> &Self {
+        self.expect(Call::Read { command, rx_len }, response)
+    }
+
+    /// Expects a transfer
+    ///
+    /// # Arguments
+    ///
+    /// `command` - Bytes written before reading
+    /// `rx_len` - Number of bytes the transfer must ask for
+    /// `response` - Bytes read, or error to return
+    pub fn expect_transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        response: Result<Vec<u8>>,
+    ) -> &Self {
+        self.expect(Call::Transfer { command, rx_len }, response)
+    }
+
+    /// Panics unless all expectations have been consumed
+    pub fn verify(&self) {
+        let remaining = self.lock().remaining();
+        if let Some(remaining) = remaining {
+            panic!("MockStream: expected calls not made: {}", remaining);
+        }
+    }
+
+    fn expect(&self, call: Call, response: Result<Vec<u8>>) -> &Self {
+        self.lock()
+            .expectations
+            .push_back(Expectation { call, response });
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Poisoning only means an earlier assertion failed in another thread
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checks `call` against the next expectation and returns its response
+    fn call(&self, call: Call) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        match state.expectations.front() {
+            Some(next) if next.call == call => {}
+            Some(next) => {
+                let next = next.call.to_string();
+                drop(state);
+                panic!("MockStream: unexpected {}, expected {}", call, next);
+            }
+            None => {
+                drop(state);
+                panic!("MockStream: unexpected {}, no more calls expected", call);
+            }
+        }
+        state.expectations.pop_front().unwrap().response
+    }
+}
+
+impl State {
+    /// Lists the expectations not consumed yet, if any
+    fn remaining(&self) -> Option<String> {
+        if self.expectations.is_empty() {
+            return None;
+        }
+        let calls: Vec<_> = self
+            .expectations
+            .iter()
+            .map(|e| e.call.to_string())
+            .collect();
+        Some(calls.join(", "))
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            return;
+        }
+        if let Some(remaining) = self.remaining() {
+            panic!(
+                "MockStream dropped with expected calls not made: {}",
+                remaining
+            );
+        }
+    }
+}
+
+impl Stream for MockStream {
+    type StreamError = std::io::Error;
+
+    fn write(&self, command: Vec<u8>) -> Result<()> {
+        self.call(Call::Write(command)).map(drop)
+    }
+
+    fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
+        self.call(Call::Write(command)).map(drop)
+    }
+
+    fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
+        self.call(Call::Read {
+            command: command.clone(),
+            rx_len,
+        })
+    }
+
+    fn read_timeout(
+        &self,
+        command: &mut Vec<u8>,
+        rx_len: usize,
+        _timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.read(command, rx_len)
+    }
+
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        _delay: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.call(Call::Transfer { command, rx_len })
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! SMBus Packet Error Checking
+
+use crate::Address;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Feeds `data` into a running SMBus PEC, i.e. a CRC-8 with polynomial
+/// x^8 + x^2 + x + 1 and an initial value of zero.
+pub fn crc8(mut crc: u8, data: &[u8]) -> u8 {
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Address byte(s) which the master puts on the wire for `address`, as
+/// covered by the PEC
+///
+/// For 10-bit addresses a read is assumed to follow a write to the same
+/// slave, so only the repeated high address byte is sent.
+pub(crate) fn address_bytes(address: Address, read: bool) -> Vec<u8> {
+    let rw = read as u8;
+    match address {
+        Address::SevenBit(address) => vec![(address as u8) << 1 | rw],
+        Address::TenBit(address) => {
+            let high = 0xF0 | ((address >> 7) as u8 & 0x06) | rw;
+            if read {
+                vec![high]
+            } else {
+                vec![high, address as u8]
+            }
+        }
+    }
+}
+
+/// PEC of a write of `data` to `address`
+pub(crate) fn write_pec(address: Address, data: &[u8]) -> u8 {
+    crc8(crc8(0, &address_bytes(address, false)), data)
+}
+
+/// PEC of a read of `data` from `address`, preceded by a write of `command`
+/// and a repeated start unless `command` is empty
+pub(crate) fn read_pec(address: Address, command: &[u8], data: &[u8]) -> u8 {
+    let mut crc = 0;
+    if !command.is_empty() {
+        crc = crc8(crc, &address_bytes(address, false));
+        crc = crc8(crc, command);
+    }
+    crc = crc8(crc, &address_bytes(address, true));
+    crc8(crc, data)
+}
+
+/// Error reported when the PEC byte received from a slave doesn't match the
+/// one computed over the rest of the transaction
+///
+/// It is returned wrapped in an `std::io::Error` of kind `InvalidData`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PecMismatch {
+    /// PEC computed over the transaction
+    pub expected: u8,
+    /// PEC sent by the slave
+    pub received: u8,
+}
+
+impl fmt::Display for PecMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PEC mismatch: expected {:#04x}, received {:#04x}",
+            self.expected, self.received
+        )
+    }
+}
+
+impl std::error::Error for PecMismatch {}
+
+impl From<PecMismatch> for Error {
+    fn from(err: PecMismatch) -> Self {
+        Error::new(ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc8_check_value() {
+        // CRC-8/SMBUS check value over the ASCII digits 1 to 9
+        assert_eq!(crc8(0, b"123456789"), 0xF4);
+        assert_eq!(crc8(crc8(0, b"1234"), b"56789"), 0xF4);
+        assert_eq!(crc8(0, &[]), 0x00);
+    }
+
+    #[test]
+    fn seven_bit_address_bytes() {
+        assert_eq!(address_bytes(Address::SevenBit(0x5A), false), [0xB4]);
+        assert_eq!(address_bytes(Address::SevenBit(0x5A), true), [0xB5]);
+    }
+
+    #[test]
+    fn ten_bit_address_bytes() {
+        // 0x2A5: high bits 0b10 go into the 11110xx header, low byte follows
+        assert_eq!(address_bytes(Address::TenBit(0x2A5), false), [0xF4, 0xA5]);
+        assert_eq!(address_bytes(Address::TenBit(0x2A5), true), [0xF5]);
+        assert_eq!(address_bytes(Address::TenBit(0x3FF), false), [0xF6, 0xFF]);
+        assert_eq!(address_bytes(Address::TenBit(0x3FF), true), [0xF7]);
+    }
+
+    #[test]
+    fn read_word_pec() {
+        // MLX90614 read word example: B4 07 B5 D2 3A with PEC 0x30
+        let pec = read_pec(Address::SevenBit(0x5A), &[0x07], &[0xD2, 0x3A]);
+        assert_eq!(pec, 0x30);
+        assert_eq!(crc8(0, &[0xB4, 0x07, 0xB5, 0xD2, 0x3A]), pec);
+    }
+
+    #[test]
+    fn receive_byte_pec() {
+        // Without a command only the read address byte precedes the data
+        let pec = read_pec(Address::SevenBit(0x5A), &[], &[0x42]);
+        assert_eq!(pec, crc8(0, &[0xB5, 0x42]));
+    }
+
+    #[test]
+    fn write_pec_covers_address() {
+        let pec = write_pec(Address::SevenBit(0x5A), &[0x07, 0xD2, 0x3A]);
+        assert_eq!(pec, crc8(0, &[0xB4, 0x07, 0xD2, 0x3A]));
+    }
+
+    #[test]
+    fn ten_bit_read_pec() {
+        // Write header and low byte, command, then only the repeated
+        // header with the read bit before the data
+        let pec = read_pec(Address::TenBit(0x2A5), &[0x10], &[0x34, 0x12]);
+        assert_eq!(crc8(0, &[0xF4, 0xA5, 0x10, 0xF5, 0x34, 0x12]), pec);
+        assert_eq!(pec, 0x9D);
+    }
+}
@@ -0,0 +1,586 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Recording transactions to a file and replaying them
+//!
+//! A [`Recorder`] wraps any `Stream` and logs every call to it. A
+//! [`Replay`] reads such a log and serves the recorded responses back,
+//! checking that the calls it receives match the recorded ones.
+//!
+//! The log is a text file with one call per line and tab-separated fields:
+//! microseconds since the start of the recording, the method called, the
+//! bytes written in hex, the number of bytes requested, and the outcome,
+//! either `ok:` followed by the bytes read in hex or `err:` followed by an
+//! [`I2cErrorKind`] name, a colon and the error message. The message leaves
+//! out the bus path, slave address and command byte, which replayed errors
+//! pick up from the `Connection` again. Lines starting with `#` are
+//! comments.
+
+use crate::{I2cError, I2cErrorKind, SmbusStream};
+use hal_stream::Stream;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// `Stream` method recorded in a log
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    Write,
+    WriteBytes,
+    Read,
+    ReadTimeout,
+    Transfer,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Write => "write",
+            Op::WriteBytes => "write_bytes",
+            Op::Read => "read",
+            Op::ReadTimeout => "read_timeout",
+            Op::Transfer => "transfer",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "write" => Op::Write,
+            "write_bytes" => Op::WriteBytes,
+            "read" => Op::Read,
+            "read_timeout" => Op::ReadTimeout,
+            "transfer" => Op::Transfer,
+            _ => return None,
+        })
+    }
+}
+
+/// A `Stream` which logs every call to an inner stream
+///
+/// Log lines are flushed as they are written, so a recording survives a
+/// crash. Failures to write the log don't affect the transactions; the
+/// first one is reported by [`Recorder::finish`].
+pub struct Recorder<S> {
+    inner: S,
+    start: Instant,
+    log: Mutex<Log>,
+}
+
+struct Log {
+    writer: BufWriter<File>,
+    error: Option<Error>,
+}
+
+impl<S: Stream<StreamError = Error>> Recorder<S> {
+    /// Creates a recorder logging to a new file
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path of the log file, which is truncated if it exists
+    /// `inner` - Stream to record
+    pub fn create<P: AsRef<Path>>(path: P, inner: S) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            writer,
+            "# i2c-rs recording started at {}.{:06}",
+            started.as_secs(),
+            started.subsec_micros()
+        )?;
+        writer.flush()?;
+        Ok(Self {
+            inner,
+            start: Instant::now(),
+            log: Mutex::new(Log {
+                writer,
+                error: None,
+            }),
+        })
+    }
+
+    /// Flushes the log and returns the inner stream, or the first error
+    /// encountered while writing the log
+    pub fn finish(self) -> Result<S> {
+        let mut log = self.log.into_inner().unwrap_or_else(|e| e.into_inner());
+        if let Some(e) = log.error.take() {
+            return Err(e);
+        }
+        log.writer.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Runs `f` on the inner stream and logs the call and its outcome
+    fn record<T, F>(&self, op: Op, tx: &[u8], rx_len: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&S) -> Result<T>,
+        T: AsRef<[u8]>,
+    {
+        let timestamp = self.start.elapsed().as_micros();
+        let result = f(&self.inner);
+        let outcome = match result {
+            Ok(ref data) => format!("ok:{}", hex(data.as_ref())),
+            Err(ref e) => format!("err:{}:{}", I2cErrorKind::of(e), escape(&message(e))),
+        };
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}",
+            timestamp,
+            op.as_str(),
+            hex(tx),
+            rx_len,
+            outcome
+        );
+
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        let written = writeln!(log.writer, "{}", line).and_then(|()| log.writer.flush());
+        if let Err(e) = written {
+            log.error.get_or_insert(e);
+        }
+        result
+    }
+}
+
+impl<S: Stream<StreamError = Error>> Stream for Recorder<S> {
+    type StreamError = Error;
+
+    fn write(&self, command: Vec<u8>) -> Result<()> {
+        self.record(Op::Write, &command.clone(), 0, |s| {
+            s.write(command).map(|()| [])
+        })
+        .map(drop)
+    }
+
+    fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
+        self.record(Op::WriteBytes, &command.clone(), 0, |s| {
+            s.write_bytes(command).map(|()| [])
+        })
+        .map(drop)
+    }
+
+    fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
+        let tx = command.clone();
+        self.record(Op::Read, &tx, rx_len, |s| s.read(command, rx_len))
+    }
+
+    fn read_timeout(
+        &self,
+        command: &mut Vec<u8>,
+        rx_len: usize,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let tx = command.clone();
+        self.record(Op::ReadTimeout, &tx, rx_len, |s| {
+            s.read_timeout(command, rx_len, timeout)
+        })
+    }
+
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        delay: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.record(Op::Transfer, &command.clone(), rx_len, |s| {
+            s.transfer(command, rx_len, delay)
+        })
+    }
+}
+
+/// SMBus transactions are emulated on top of the recorded raw transfers, so
+/// that they show up in the log. The inner stream's bus lock, if any, still
+/// holds across [`SmbusStream::exclusive`] sequences.
+impl<S: SmbusStream> SmbusStream for Recorder<S> {
+    fn exclusive(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        self.inner.exclusive(f)
+    }
+}
+
+/// A recorded call
+struct Entry {
+    line: usize,
+    op: Op,
+    tx: Vec<u8>,
+    rx_len: usize,
+    outcome: std::result::Result<Vec<u8>, (I2cErrorKind, String)>,
+}
+
+/// A `Stream` which serves the responses from a [`Recorder`] log
+///
+/// Each call must match the next recorded call in method, bytes written
+/// and number of bytes requested. A call which doesn't is a divergence: it
+/// fails with an `InvalidData` error, is noted in
+/// [`divergences`](Replay::divergences), and leaves the recorded call in
+/// place. Recorded errors are returned as [`I2cError`]s of the recorded
+/// kind. Delays and timeouts are not reproduced.
+///
+/// Clones share the same position in the log.
+#[derive(Clone)]
+pub struct Replay {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+struct ReplayState {
+    entries: VecDeque<Entry>,
+    divergences: Vec<String>,
+}
+
+impl Replay {
+    /// Loads a log written by a [`Recorder`]
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path of the log file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut entries = VecDeque::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = parse_entry(index + 1, &line).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("malformed recording at line {}", index + 1),
+                )
+            })?;
+            entries.push_back(entry);
+        }
+        Ok(Self {
+            state: Arc::new(Mutex::new(ReplayState {
+                entries,
+                divergences: Vec::new(),
+            })),
+        })
+    }
+
+    /// Descriptions of all divergences so far
+    pub fn divergences(&self) -> Vec<String> {
+        self.lock().divergences.clone()
+    }
+
+    /// Number of recorded calls not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Fails if there were divergences or recorded calls are left
+    pub fn verify(&self) -> Result<()> {
+        let state = self.lock();
+        if let Some(first) = state.divergences.first() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} divergence(s) from recording, first: {}",
+                    state.divergences.len(),
+                    first
+                ),
+            ));
+        }
+        if let Some(next) = state.entries.front() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} recorded call(s) not replayed, starting at line {}",
+                    state.entries.len(),
+                    next.line
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Matches a call against the next recorded one and returns its outcome
+    fn replay(&self, op: Op, tx: &[u8], rx_len: usize) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        let divergence = match state.entries.front() {
+            Some(next) if next.op == op && next.tx == tx && next.rx_len == rx_len => None,
+            Some(next) => Some(format!(
+                "line {}: expected {}({}, {}), got {}({}, {})",
+                next.line,
+                next.op.as_str(),
+                hex(&next.tx),
+                next.rx_len,
+                op.as_str(),
+                hex(tx),
+                rx_len
+            )),
+            None => Some(format!(
+                "end of recording: got {}({}, {})",
+                op.as_str(),
+                hex(tx),
+                rx_len
+            )),
+        };
+        if let Some(divergence) = divergence {
+            state.divergences.push(divergence.clone());
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("replay divergence at {}", divergence),
+            ));
+        }
+
+        match state.entries.pop_front().unwrap().outcome {
+            Ok(data) => Ok(data),
+            Err((kind, message)) => Err(I2cError::new(kind, &message).into()),
+        }
+    }
+}
+
+impl Stream for Replay {
+    type StreamError = Error;
+
+    fn write(&self, command: Vec<u8>) -> Result<()> {
+        self.replay(Op::Write, &command, 0).map(drop)
+    }
+
+    fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
+        self.replay(Op::WriteBytes, &command, 0).map(drop)
+    }
+
+    fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
+        self.replay(Op::Read, command, rx_len)
+    }
+
+    fn read_timeout(
+        &self,
+        command: &mut Vec<u8>,
+        rx_len: usize,
+        _timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.replay(Op::ReadTimeout, command, rx_len)
+    }
+
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        _delay: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.replay(Op::Transfer, &command, rx_len)
+    }
+}
+
+fn parse_entry(line: usize, s: &str) -> Option<Entry> {
+    let mut fields = s.splitn(5, '\t');
+    let _timestamp: u128 = fields.next()?.parse().ok()?;
+    let op = Op::parse(fields.next()?)?;
+    let tx = unhex(fields.next()?)?;
+    let rx_len = fields.next()?.parse().ok()?;
+    let outcome = fields.next()?;
+    let outcome = if let Some(data) = outcome.strip_prefix("ok:") {
+        Ok(unhex(data)?)
+    } else {
+        let mut parts = outcome.strip_prefix("err:")?.splitn(2, ':');
+        let kind = parts.next()?.parse().ok()?;
+        Err((kind, unescape(parts.next().unwrap_or(""))))
+    };
+    Some(Entry {
+        line,
+        op,
+        tx,
+        rx_len,
+        outcome,
+    })
+}
+
+/// Message of `err` without its context
+fn message(err: &Error) -> String {
+    match err.get_ref().and_then(|e| e.downcast_ref::<I2cError>()) {
+        Some(err) => err.message(),
+        None => err.to_string(),
+    }
+}
+
+/// Lowercase hex dump of `data`, without separators
+pub(crate) fn hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for byte in data {
+        let _ = write!(s, "{:02x}", byte);
+    }
+    s
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Keeps an error message on one log line
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, Command, Connection, MockStream};
+    use std::path::PathBuf;
+
+    fn log_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("i2c-rs-{}-{}.log", name, std::process::id()))
+    }
+
+    fn connection<S: Stream<StreamError = Error> + Send + 'static>(stream: S) -> Connection {
+        Connection::new(Box::new(stream)).located("/dev/i2c-1", Address::SevenBit(0x40))
+    }
+
+    /// Runs a fixed session, returning every outcome with errors rendered
+    /// as their kind and message
+    fn session(
+        connection: &Connection,
+    ) -> Vec<std::result::Result<Vec<u8>, (I2cErrorKind, String)>> {
+        let command = |cmd: u8, data: Vec<u8>| Command { cmd, data };
+        vec![
+            connection.write(command(0x01, vec![0xAA])).map(|()| vec![]),
+            connection.read(command(0x02, vec![]), 2),
+            connection.read(command(0x03, vec![]), 1),
+            connection.write(command(0x04, vec![0x00])).map(|()| vec![]),
+            connection.transfer(command(0x05, vec![]), 1, Duration::from_millis(0)),
+            connection.read(command(0x06, vec![]), 1),
+        ]
+        .into_iter()
+        .map(|result| result.map_err(|e| (e.kind(), e.to_string())))
+        .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = log_path("round-trip");
+        let mock = MockStream::new();
+        mock.expect_write(vec![0x01, 0xAA], Ok(()));
+        mock.expect_read(vec![0x02], 2, Ok(vec![0x12, 0x34]));
+        mock.expect_read(vec![0x03], 1, Err(Error::from_raw_os_error(libc::ENXIO)));
+        let invalid = I2cError::new(I2cErrorKind::InvalidArgument, "bad\tvalue\\\n").with_context(
+            Some("/dev/i2c-1"),
+            Some(Address::SevenBit(0x40)),
+            None,
+        );
+        mock.expect_write(vec![0x04, 0x00], Err(invalid.into()));
+        mock.expect_transfer(vec![0x05], 1, Err(Error::other("bus glitch")));
+        mock.expect_read(vec![0x06], 1, Ok(vec![]));
+
+        let recorder = Recorder::create(&path, mock.clone()).unwrap();
+        let recorded = session(&connection(recorder));
+        mock.verify();
+
+        let replay = Replay::open(&path).unwrap();
+        let replayed = session(&connection(replay.clone()));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replay.divergences(), Vec::<String>::new());
+        replay.verify().unwrap();
+        assert_eq!(replayed, recorded);
+
+        let kinds: Vec<_> = recorded
+            .iter()
+            .map(|r| r.as_ref().err().map(|e| e.0))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                None,
+                None,
+                Some(I2cErrorKind::Nack),
+                Some(I2cErrorKind::InvalidArgument),
+                Some(I2cErrorKind::Io),
+                None
+            ]
+        );
+        assert_eq!(
+            replayed[3].as_ref().unwrap_err().1,
+            "invalid argument: bad\tvalue\\\n (/dev/i2c-1 slave 0x40 command 0x04)"
+        );
+    }
+
+    #[test]
+    fn divergence() {
+        let path = log_path("divergence");
+        let mock = MockStream::new();
+        mock.expect_write(vec![0x01], Ok(()));
+        Recorder::create(&path, mock)
+            .unwrap()
+            .write(vec![0x01])
+            .unwrap();
+
+        let replay = Replay::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let err = replay.write(vec![0x02]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            replay.divergences(),
+            ["line 2: expected write(01, 0), got write(02, 0)"]
+        );
+        assert_eq!(replay.remaining(), 1);
+        assert!(replay.verify().is_err());
+    }
+
+    #[test]
+    fn escaping() {
+        for s in [
+            "",
+            "plain",
+            "tab\there",
+            "line\nbreak",
+            "back\\slash\\",
+            "\\t",
+        ] {
+            let escaped = escape(s);
+            assert!(!escaped.contains('\t') && !escaped.contains('\n'));
+            assert_eq!(unescape(&escaped), s);
+        }
+        assert_eq!(
+            unhex(&hex(&[0x00, 0x7F, 0xFF])),
+            Some(vec![0x00, 0x7F, 0xFF])
+        );
+        assert_eq!(unhex("abc"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+}
@@ -0,0 +1,605 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Typed register values
+//!
+//! ```
+//! use i2c_rs::{Connection, Endian, MockStream};
+//!
+//! let mock = MockStream::new();
+//! mock.expect_read(vec![0x05], 2, Ok(vec![0xFF, 0x38]));
+//! mock.expect_write(vec![0x06, 0x12, 0x34, 0x56, 0x78], Ok(()));
+//!
+//! let connection = Connection::new(Box::new(mock.clone()));
+//! let temperature: i16 = connection.read_register(0x05, Endian::Big).unwrap();
+//! assert_eq!(temperature, -200);
+//! connection
+//!     .write_register(0x06, 0x12345678u32, Endian::Big)
+//!     .unwrap();
+//! ```
+//!
+//! Registers made up of bit fields can be described with [`bitfield!`],
+//! with [`field_enum!`] for fields that hold one of a set of values:
+//!
+//! ```
+//! use i2c_rs::{bitfield, field_enum, Connection, MockStream};
+//!
+//! field_enum! {
+//!     /// Operating mode
+//!     pub enum Mode {
+//!         Sleep = 0,
+//!         Normal = 1,
+//!         Burst = 3,
+//!     }
+//! }
+//!
+//! bitfield! {
+//!     /// Configuration register
+//!     pub struct Config(u8) @ 0x01 {
+//!         /// Operating mode
+//!         mode, set_mode: Mode @ 0..=1,
+//!         /// ADC gain
+//!         gain, set_gain: u8 @ 2..=4,
+//!         /// Interrupt enable
+//!         interrupt, set_interrupt: bool @ 7,
+//!     }
+//! }
+//!
+//! let mock = MockStream::new();
+//! mock.expect_read(vec![0x01], 1, Ok(vec![0x88]));
+//! mock.expect_write(vec![0x01, 0x89], Ok(()));
+//!
+//! let connection = Connection::new(Box::new(mock.clone()));
+//! let config = Config::modify(&connection, |c| c.set_mode(Mode::Normal)).unwrap();
+//! assert_eq!(config.gain(), 2);
+//! assert!(config.interrupt());
+//! ```
+//!
+//! [`bitfield!`]: macro@crate::bitfield
+//! [`field_enum!`]: macro@crate::field_enum
+
+use crate::{I2cError, I2cErrorKind, I2cResult};
+use std::fmt;
+
+/// Byte order of multi-byte register values and addresses
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Endian {
+    /// Most significant byte first
+    Big,
+    /// Least significant byte first
+    Little,
+}
+
+/// Register address of 0 to 4 bytes, as written ahead of a command's data
+///
+/// Plain bytes convert into single-byte addresses.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct RegisterAddress {
+    value: u32,
+    width: usize,
+    endian: Endian,
+}
+
+impl RegisterAddress {
+    /// Largest register address width, in bytes
+    pub const MAX_WIDTH: usize = 4;
+
+    /// No register address at all, for devices which take plain reads and
+    /// writes
+    pub const NONE: RegisterAddress = RegisterAddress {
+        value: 0,
+        width: 0,
+        endian: Endian::Big,
+    };
+
+    /// Creates a register address of any supported width
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Register address
+    /// `width` - Width of the address on the wire, from 0 to 4 bytes
+    /// `endian` - Byte order of the address on the wire
+    pub fn new(value: u32, width: usize, endian: Endian) -> I2cResult<Self> {
+        if width > Self::MAX_WIDTH || (width < Self::MAX_WIDTH && value >> (8 * width) != 0) {
+            return Err(I2cError::new(
+                I2cErrorKind::InvalidArgument,
+                &format!(
+                    "register address {:#x} does not fit in {} bytes",
+                    value, width
+                ),
+            ));
+        }
+        Ok(Self {
+            value,
+            width,
+            endian,
+        })
+    }
+
+    /// Creates a single-byte register address
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Register address
+    pub fn u8(value: u8) -> Self {
+        Self {
+            value: value.into(),
+            width: 1,
+            endian: Endian::Big,
+        }
+    }
+
+    /// Creates a 16-bit register address
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Register address
+    /// `endian` - Byte order of the address on the wire
+    pub fn u16(value: u16, endian: Endian) -> Self {
+        Self {
+            value: value.into(),
+            width: 2,
+            endian,
+        }
+    }
+
+    /// Creates a 32-bit register address
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Register address
+    /// `endian` - Byte order of the address on the wire
+    pub fn u32(value: u32, endian: Endian) -> Self {
+        Self {
+            value,
+            width: 4,
+            endian,
+        }
+    }
+
+    /// Register address
+    pub fn value(self) -> u32 {
+        self.value
+    }
+
+    /// Width of the address on the wire, in bytes
+    pub fn width(self) -> usize {
+        self.width
+    }
+
+    /// Byte order of the address on the wire
+    pub fn endian(self) -> Endian {
+        self.endian
+    }
+
+    /// The address as written on the wire
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self.endian {
+            Endian::Big => self.value.to_be_bytes()[4 - self.width..].to_vec(),
+            Endian::Little => self.value.to_le_bytes()[..self.width].to_vec(),
+        }
+    }
+
+    /// The address as an SMBus-style command byte, if it is a single byte
+    pub(crate) fn command_byte(self) -> Option<u8> {
+        if self.width == 1 {
+            Some(self.value as u8)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<u8> for RegisterAddress {
+    fn from(value: u8) -> Self {
+        Self::u8(value)
+    }
+}
+
+/// A value held in a device register
+pub trait RegisterValue: Copy + Sized {
+    /// Size of the value on the wire, in bytes
+    const SIZE: usize;
+
+    /// Decodes a value from exactly [`RegisterValue::SIZE`] bytes
+    fn from_bytes(bytes: &[u8], endian: Endian) -> Self;
+
+    /// Encodes the value into [`RegisterValue::SIZE`] bytes
+    fn to_bytes(self, endian: Endian) -> Vec<u8>;
+}
+
+macro_rules! register_value {
+    ($($ty:ty),*) => {$(
+        impl RegisterValue for $ty {
+            const SIZE: usize = std::mem::size_of::<$ty>();
+
+            fn from_bytes(bytes: &[u8], endian: Endian) -> Self {
+                let mut buf = [0; std::mem::size_of::<$ty>()];
+                buf.copy_from_slice(bytes);
+                match endian {
+                    Endian::Big => <$ty>::from_be_bytes(buf),
+                    Endian::Little => <$ty>::from_le_bytes(buf),
+                }
+            }
+
+            fn to_bytes(self, endian: Endian) -> Vec<u8> {
+                match endian {
+                    Endian::Big => self.to_be_bytes().to_vec(),
+                    Endian::Little => self.to_le_bytes().to_vec(),
+                }
+            }
+        }
+    )*};
+}
+
+register_value!(u8, i8, u16, i16, u32, i32);
+
+/// A register made up of bit fields, as defined by [`bitfield!`]
+///
+/// [`bitfield!`]: macro@crate::bitfield
+pub trait Bitfield: Copy + Sized {
+    /// Type of the whole register value
+    type Value: RegisterValue + fmt::LowerHex;
+
+    /// Register address
+    ///
+    /// Only single-byte addresses are supported. Registers behind a wider
+    /// [`RegisterAddress`] are read and written as plain values with
+    /// [`Connection::read_register`](crate::Connection::read_register) and
+    /// [`Connection::write_register`](crate::Connection::write_register).
+    const ADDRESS: u8;
+
+    /// Byte order of the register value
+    const ENDIAN: Endian;
+
+    /// Creates the register from its value, if every field holds a valid
+    /// value
+    fn from_value(value: Self::Value) -> Option<Self>;
+
+    /// Value of the whole register
+    fn value(self) -> Self::Value;
+}
+
+/// Type of a field in a [`bitfield!`] register
+///
+/// [`bitfield!`]: macro@crate::bitfield
+pub trait Field: Copy + Sized {
+    /// Fewest bits a field of this type can have
+    const MIN_WIDTH: u32;
+
+    /// Most bits a field of this type can have
+    const MAX_WIDTH: u32;
+
+    /// Decodes the field from its bits, if they hold a valid value
+    fn from_bits(bits: u32) -> Option<Self>;
+
+    /// Encodes the field into its bits
+    fn into_bits(self) -> u32;
+}
+
+impl Field for bool {
+    const MIN_WIDTH: u32 = 1;
+    const MAX_WIDTH: u32 = 1;
+
+    fn from_bits(bits: u32) -> Option<Self> {
+        Some(bits != 0)
+    }
+
+    fn into_bits(self) -> u32 {
+        self as u32
+    }
+}
+
+macro_rules! field {
+    ($($ty:ty),*) => {$(
+        impl Field for $ty {
+            const MIN_WIDTH: u32 = 1;
+            const MAX_WIDTH: u32 = <$ty>::BITS;
+
+            fn from_bits(bits: u32) -> Option<Self> {
+                Some(bits as $ty)
+            }
+
+            fn into_bits(self) -> u32 {
+                self as u32
+            }
+        }
+    )*};
+}
+
+field!(u8, u16, u32);
+
+/// Mask for the lowest `width` bits
+#[doc(hidden)]
+pub const fn mask(width: u32) -> u32 {
+    u32::MAX >> (32 - width)
+}
+
+/// Defines an enum which can be used as a [`bitfield!`] field
+///
+/// Variants must have explicit values. `Clone`, `Copy`, `Debug`, `Eq` and
+/// `PartialEq` are derived. Bits that don't match any variant are rejected
+/// when a register is read.
+///
+/// [`bitfield!`]: macro@crate::bitfield
+#[macro_export]
+macro_rules! field_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($(#[$vmeta:meta])* $variant:ident = $value:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        $vis enum $name {
+            $($(#[$vmeta])* $variant = $value),*
+        }
+
+        impl $crate::register::Field for $name {
+            const MIN_WIDTH: u32 = {
+                let mut max: u32 = 0;
+                $(
+                    if $value > max {
+                        max = $value;
+                    }
+                )*
+                32 - max.leading_zeros()
+            };
+            const MAX_WIDTH: u32 = 32;
+
+            fn from_bits(bits: u32) -> Option<Self> {
+                $(
+                    if bits == $value {
+                        return Some($name::$variant);
+                    }
+                )*
+                None
+            }
+
+            fn into_bits(self) -> u32 {
+                self as u32
+            }
+        }
+    };
+}
+
+/// Defines a register made up of bit fields
+///
+/// The register is a struct wrapping the register value, with a getter and
+/// a setter for every field and methods to read, write and modify the
+/// register through a [`Connection`](crate::Connection). Fields are given as
+/// `getter, setter: Type @ bit` or `getter, setter: Type @ low..=high`,
+/// where the type is `bool`, an unsigned integer or a [`field_enum!`]
+/// named without a path.
+/// Fields which don't fit in the register, or whose width doesn't suit
+/// their type, are rejected at compile time. Integer fields keep only as
+/// many low bits of a new value as the field is wide.
+///
+/// The register value can be `u8`, `u16` or `u32`; multi-byte values are
+/// big endian unless an [`Endian`] variant is given after the address, e.g.
+/// `pub struct Status(u16) @ 0x02, Little { .. }`. The address itself is a
+/// single byte (see [`Bitfield::ADDRESS`]).
+///
+/// [`field_enum!`]: macro@crate::field_enum
+#[macro_export]
+macro_rules! bitfield {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident($ty:ty) @ $addr:literal $(, $endian:ident)? {
+            $(
+                $(#[$fmeta:meta])*
+                $get:ident, $set:ident: $fty:ident @ $lo:literal $(..= $hi:literal)?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+        $vis struct $name($ty);
+
+        impl $name {
+            /// Register address
+            pub const ADDRESS: u8 = $addr;
+
+            /// Reads the register
+            pub fn read(connection: &$crate::Connection) -> $crate::I2cResult<Self> {
+                connection.read_bitfield()
+            }
+
+            /// Writes the register
+            pub fn write(self, connection: &$crate::Connection) -> $crate::I2cResult<()> {
+                connection.write_bitfield(self)
+            }
+
+            /// Reads the register, applies `f` and writes the result back,
+            /// returning the new value
+            pub fn modify<F>(connection: &$crate::Connection, f: F) -> $crate::I2cResult<Self>
+            where
+                F: FnOnce(&mut Self),
+            {
+                connection.modify_bitfield(f)
+            }
+
+            /// Creates the register from its value, if every field holds a
+            /// valid value
+            pub fn from_bits(bits: $ty) -> Option<Self> {
+                let register = $name(bits);
+                $(
+                    <$fty as $crate::register::Field>::from_bits(
+                        register.bits_at($lo, $crate::bitfield!(@hi $lo $($hi)?)),
+                    )?;
+                )*
+                Some(register)
+            }
+
+            /// Value of the whole register
+            pub fn bits(self) -> $ty {
+                self.0
+            }
+
+            $(
+                $(#[$fmeta])*
+                pub fn $get(self) -> $fty {
+                    let bits = self.bits_at($lo, $crate::bitfield!(@hi $lo $($hi)?));
+                    <$fty as $crate::register::Field>::from_bits(bits)
+                        .expect("register holds an invalid field value")
+                }
+
+                $(#[$fmeta])*
+                pub fn $set(&mut self, value: $fty) {
+                    let bits = <$fty as $crate::register::Field>::into_bits(value);
+                    self.set_bits_at($lo, $crate::bitfield!(@hi $lo $($hi)?), bits);
+                }
+            )*
+
+            #[allow(dead_code)]
+            fn bits_at(self, lo: u32, hi: u32) -> u32 {
+                (self.0 as u32 >> lo) & $crate::register::mask(hi - lo + 1)
+            }
+
+            #[allow(dead_code)]
+            fn set_bits_at(&mut self, lo: u32, hi: u32, bits: u32) {
+                let mask = $crate::register::mask(hi - lo + 1) << lo;
+                self.0 = ((self.0 as u32 & !mask) | ((bits << lo) & mask)) as $ty;
+            }
+        }
+
+        $(
+            const _: () = {
+                let lo: u32 = $lo;
+                let hi: u32 = $crate::bitfield!(@hi $lo $($hi)?);
+                assert!(lo <= hi, "field bits must be given as low..=high");
+                assert!(hi < <$ty>::BITS, "field does not fit in the register");
+                assert!(
+                    <$fty as $crate::register::Field>::MIN_WIDTH <= hi - lo + 1,
+                    "field is too narrow for its type"
+                );
+                assert!(
+                    hi - lo < <$fty as $crate::register::Field>::MAX_WIDTH,
+                    "field is too wide for its type"
+                );
+            };
+        )*
+
+        impl $crate::register::Bitfield for $name {
+            type Value = $ty;
+            const ADDRESS: u8 = $addr;
+            const ENDIAN: $crate::Endian = $crate::bitfield!(@endian $($endian)?);
+
+            fn from_value(value: $ty) -> Option<Self> {
+                Self::from_bits(value)
+            }
+
+            fn value(self) -> $ty {
+                self.0
+            }
+        }
+    };
+    (@hi $lo:literal) => { $lo };
+    (@hi $lo:literal $hi:literal) => { $hi };
+    (@endian) => { $crate::Endian::Big };
+    (@endian $endian:ident) => { $crate::Endian::$endian };
+}
+
+/// Decodes `count` consecutive values from `bytes`, if it holds exactly
+/// that many
+pub(crate) fn decode<T: RegisterValue>(
+    bytes: &[u8],
+    count: usize,
+    endian: Endian,
+) -> Result<Vec<T>, String> {
+    let expected = T::SIZE * count;
+    if bytes.len() != expected {
+        return Err(format!(
+            "expected {} bytes of register data, got {}",
+            expected,
+            bytes.len()
+        ));
+    }
+    Ok(bytes
+        .chunks(T::SIZE)
+        .map(|chunk| T::from_bytes(chunk, endian))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_byte_order() {
+        let table = [
+            (RegisterAddress::NONE, vec![]),
+            (RegisterAddress::u8(0x2A), vec![0x2A]),
+            (RegisterAddress::u16(0x1F40, Endian::Big), vec![0x1F, 0x40]),
+            (
+                RegisterAddress::u16(0x1F40, Endian::Little),
+                vec![0x40, 0x1F],
+            ),
+            (
+                RegisterAddress::u32(0x0102_0304, Endian::Big),
+                vec![0x01, 0x02, 0x03, 0x04],
+            ),
+            (
+                RegisterAddress::u32(0x0102_0304, Endian::Little),
+                vec![0x04, 0x03, 0x02, 0x01],
+            ),
+            (
+                RegisterAddress::new(0x01_0203, 3, Endian::Big).unwrap(),
+                vec![0x01, 0x02, 0x03],
+            ),
+            (
+                RegisterAddress::new(0x01_0203, 3, Endian::Little).unwrap(),
+                vec![0x03, 0x02, 0x01],
+            ),
+        ];
+        for (address, bytes) in &table {
+            assert_eq!(&address.to_bytes(), bytes, "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn new_rejects_values_wider_than_width() {
+        let table = [(1, 0), (0x100, 1), (0x1_0000, 2), (0x100_0000, 3), (0, 5)];
+        for &(value, width) in &table {
+            let err = RegisterAddress::new(value, width, Endian::Big).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                I2cErrorKind::InvalidArgument,
+                "{:#x} in {} bytes",
+                value,
+                width
+            );
+        }
+        assert_eq!(
+            RegisterAddress::new(0, 0, Endian::Big).unwrap(),
+            RegisterAddress::NONE
+        );
+        assert_eq!(
+            RegisterAddress::new(0xFFFF_FFFF, 4, Endian::Big)
+                .unwrap()
+                .value(),
+            0xFFFF_FFFF
+        );
+    }
+
+    #[test]
+    fn command_byte_only_for_single_byte_addresses() {
+        assert_eq!(RegisterAddress::u8(0x10).command_byte(), Some(0x10));
+        assert_eq!(RegisterAddress::NONE.command_byte(), None);
+        assert_eq!(RegisterAddress::u16(0x10, Endian::Big).command_byte(), None);
+    }
+}
@@ -0,0 +1,302 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Retrying failed transactions
+
+use crate::{I2cError, I2cErrorKind, I2cResult};
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::thread;
+use std::time::Duration;
+
+/// Delay between attempts
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backoff {
+    /// Retry immediately
+    None,
+    /// Wait the same time before every retry
+    Fixed(Duration),
+    /// Wait `initial` before the first retry and double the delay for
+    /// every further retry, up to `max`
+    Exponential {
+        /// Delay before the first retry
+        initial: Duration,
+        /// Upper bound for the delay
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    /// Delay before retry number `retry`, counting from zero
+    fn delay(self, retry: u32) -> Duration {
+        match self {
+            Backoff::None => Duration::from_secs(0),
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .checked_mul(1 << retry.min(31))
+                .map_or(max, |delay| delay.min(max)),
+        }
+    }
+}
+
+/// Which failed transactions are retried, how often and how far apart
+///
+/// The default policy makes a single attempt, i.e. never retries.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Backoff,
+    retryable: Vec<I2cErrorKind>,
+}
+
+impl RetryPolicy {
+    /// Creates a policy which retries NACKs and lost arbitration without
+    /// delay
+    ///
+    /// # Arguments
+    ///
+    /// `max_attempts` - Total number of attempts, including the first one
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff: Backoff::None,
+            retryable: vec![I2cErrorKind::Nack, I2cErrorKind::ArbitrationLost],
+        }
+    }
+
+    /// Creates a policy which never retries
+    pub fn never() -> Self {
+        Self::new(1)
+    }
+
+    /// Sets the delay between attempts
+    ///
+    /// # Arguments
+    ///
+    /// `backoff` - Delay between attempts
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets which kinds of errors are retried
+    ///
+    /// # Arguments
+    ///
+    /// `kinds` - Retryable error kinds
+    pub fn retry_on(mut self, kinds: &[I2cErrorKind]) -> Self {
+        self.retryable = kinds.to_vec();
+        self
+    }
+
+    /// Total number of attempts, including the first one
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Whether `err` is worth retrying under this policy
+    pub fn is_retryable(&self, err: &I2cError) -> bool {
+        self.retryable.contains(&err.kind())
+    }
+
+    /// Runs `f` until it succeeds, fails with an error that isn't
+    /// retryable or runs out of attempts
+    pub(crate) fn run<T, F>(&self, mut f: F) -> I2cResult<Retried<T>>
+    where
+        F: FnMut() -> I2cResult<T>,
+    {
+        let mut retries = 0;
+        loop {
+            match f() {
+                Ok(value) => return Ok(Retried { value, retries }),
+                Err(e) if retries + 1 < self.max_attempts && self.is_retryable(&e) => {
+                    thread::sleep(self.backoff.delay(retries));
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Like [`RetryPolicy::run`], but awaits the attempts and the backoff
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn run_async<T, F, Fut>(&self, mut f: F) -> I2cResult<Retried<T>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = I2cResult<T>>,
+    {
+        let mut retries = 0;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(Retried { value, retries }),
+                Err(e) if retries + 1 < self.max_attempts && self.is_retryable(&e) => {
+                    tokio::time::sleep(self.backoff.delay(retries)).await;
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::never()
+    }
+}
+
+/// Result of a transaction together with the number of retries it took
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Retried<T> {
+    /// Result of the successful attempt
+    pub value: T,
+    /// Number of failed attempts before the successful one
+    pub retries: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Closure failing with `kind` for the first `failures` calls, counting
+    /// every call in `calls`
+    fn failing(
+        calls: &Cell<u32>,
+        failures: u32,
+        kind: I2cErrorKind,
+    ) -> impl FnMut() -> I2cResult<u32> + '_ {
+        move || {
+            calls.set(calls.get() + 1);
+            if calls.get() <= failures {
+                Err(I2cError::new(kind, "failed"))
+            } else {
+                Ok(calls.get())
+            }
+        }
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let calls = Cell::new(0);
+        let retried = RetryPolicy::new(3)
+            .run(failing(&calls, 2, I2cErrorKind::Nack))
+            .unwrap();
+        assert_eq!(
+            retried,
+            Retried {
+                value: 3,
+                retries: 2
+            }
+        );
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn stops_after_max_attempts() {
+        let calls = Cell::new(0);
+        let err = RetryPolicy::new(3)
+            .run(failing(&calls, 5, I2cErrorKind::ArbitrationLost))
+            .unwrap_err();
+        assert_eq!(err.kind(), I2cErrorKind::ArbitrationLost);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn single_attempt_without_retries() {
+        let calls = Cell::new(0);
+        let retried = RetryPolicy::new(0)
+            .run(failing(&calls, 0, I2cErrorKind::Nack))
+            .unwrap();
+        assert_eq!(retried.retries, 0);
+
+        let calls = Cell::new(0);
+        assert!(RetryPolicy::never()
+            .run(failing(&calls, 1, I2cErrorKind::Nack))
+            .is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn only_retryable_kinds_are_retried() {
+        let calls = Cell::new(0);
+        let err = RetryPolicy::new(5)
+            .run(failing(&calls, 1, I2cErrorKind::Timeout))
+            .unwrap_err();
+        assert_eq!(err.kind(), I2cErrorKind::Timeout);
+        assert_eq!(calls.get(), 1);
+
+        let calls = Cell::new(0);
+        let policy = RetryPolicy::new(5).retry_on(&[I2cErrorKind::Timeout]);
+        let retried = policy
+            .run(failing(&calls, 1, I2cErrorKind::Timeout))
+            .unwrap();
+        assert_eq!(retried.retries, 1);
+
+        let calls = Cell::new(0);
+        assert!(policy.run(failing(&calls, 1, I2cErrorKind::Nack)).is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn fixed_backoff() {
+        let backoff = Backoff::Fixed(Duration::from_millis(5));
+        assert_eq!(backoff.delay(0), Duration::from_millis(5));
+        assert_eq!(backoff.delay(10), Duration::from_millis(5));
+        assert_eq!(Backoff::None.delay(3), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_max() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+        };
+        let delays: Vec<_> = (0..6).map(|retry| backoff.delay(retry)).collect();
+        let expected: Vec<_> = [10, 20, 40, 80, 100, 100]
+            .iter()
+            .map(|&ms| Duration::from_millis(ms))
+            .collect();
+        assert_eq!(delays, expected);
+    }
+
+    #[test]
+    fn exponential_backoff_large_retry_counts() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_nanos(1),
+            max: Duration::from_secs(u64::MAX),
+        };
+        // The shift saturates at 2^31 instead of overflowing
+        assert_eq!(backoff.delay(31), Duration::from_nanos(1 << 31));
+        assert_eq!(backoff.delay(32), Duration::from_nanos(1 << 31));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_nanos(1 << 31));
+
+        let max = Duration::from_secs(60);
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_secs(1),
+            max,
+        };
+        assert_eq!(backoff.delay(31), max);
+        assert_eq!(backoff.delay(1000), max);
+
+        // A delay too large for a Duration is capped rather than panicking
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_secs(u64::MAX / 2),
+            max,
+        };
+        assert_eq!(backoff.delay(31), max);
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Bus scanning, equivalent to `i2cdetect`
+
+use i2c_linux::{Functionality, I2c, SmbusReadWrite};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeInclusive;
+
+/// Addresses scanned by `i2cdetect` by default. The rest of the 7-bit
+/// address space is reserved.
+pub const DEFAULT_RANGE: RangeInclusive<u16> = 0x08..=0x77;
+
+/// How addresses are probed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Probe {
+    /// Read a byte from addresses where EEPROMs live (`0x30..=0x37` and
+    /// `0x50..=0x5F`) and issue a quick write everywhere else, as
+    /// `i2cdetect` does. Quick writes can corrupt EEPROMs and reads can
+    /// lock up some write-only chips, so this is the safest choice.
+    Auto,
+    /// Probe every address with an SMBus Quick Command (write)
+    QuickWrite,
+    /// Probe every address with an SMBus Receive Byte
+    ReadByte,
+}
+
+/// Outcome for an address which is in use
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScanStatus {
+    /// A device acknowledged the probe
+    Responding,
+    /// The address is claimed by a kernel driver and was not probed
+    Busy,
+}
+
+/// An address found in use by a scan
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScanEntry {
+    /// 7-bit slave address
+    pub address: u16,
+    /// Why the address is considered in use
+    pub status: ScanStatus,
+}
+
+/// Scans a bus for responding devices, probing like `i2cdetect`
+///
+/// # Arguments
+///
+/// `path` - Path to I2C adapter
+/// `range` - 7-bit addresses to scan, e.g. [`DEFAULT_RANGE`]
+pub fn scan(path: &str, range: RangeInclusive<u16>) -> Result<Vec<ScanEntry>> {
+    scan_with(path, range, Probe::Auto)
+}
+
+/// Scans a bus for responding devices using the given probing method
+///
+/// With [`Probe::Auto`], addresses whose safe probe transaction isn't
+/// supported by the adapter are skipped rather than probed the other way,
+/// as `i2cdetect` does, so they never appear in the result.
+///
+/// # Arguments
+///
+/// `path` - Path to I2C adapter
+/// `range` - 7-bit addresses to scan, e.g. [`DEFAULT_RANGE`]
+/// `probe` - How addresses are probed
+pub fn scan_with(path: &str, range: RangeInclusive<u16>, probe: Probe) -> Result<Vec<ScanEntry>> {
+    if *range.end() > 0x7F {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("scan range end {:#04x} is not a 7-bit address", range.end()),
+        ));
+    }
+
+    let mut i2c = I2c::from_path(path)?;
+    let functionality = i2c.i2c_functionality()?;
+    let quick = functionality.contains(Functionality::SMBUS_QUICK);
+    let read_byte = functionality.contains(Functionality::SMBUS_READ_BYTE);
+    if let (Probe::Auto, false, false)
+    | (Probe::QuickWrite, false, _)
+    | (Probe::ReadByte, _, false) = (probe, quick, read_byte)
+    {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "{} does not support the transactions needed to probe with {:?}",
+                path, probe
+            ),
+        ));
+    }
+
+    let mut found = Vec::new();
+    for address in range {
+        let method = match probe_method(probe, address, quick, read_byte) {
+            Some(method) => method,
+            None => continue,
+        };
+
+        match i2c.smbus_set_slave_address(address, false) {
+            Ok(()) => {}
+            Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                found.push(ScanEntry {
+                    address,
+                    status: ScanStatus::Busy,
+                });
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let responded = match method {
+            Method::ReadByte => i2c.smbus_read_byte().is_ok(),
+            Method::QuickWrite => i2c.smbus_write_quick(SmbusReadWrite::Write).is_ok(),
+        };
+        if responded {
+            found.push(ScanEntry {
+                address,
+                status: ScanStatus::Responding,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Transaction used to probe a single address
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Method {
+    QuickWrite,
+    ReadByte,
+}
+
+/// Picks the transaction to probe `address` with, or `None` if the address
+/// should be skipped because its safe transaction isn't supported
+///
+/// # Arguments
+///
+/// `probe` - How addresses are probed
+/// `address` - 7-bit slave address
+/// `quick` - Whether the adapter supports SMBus Quick Command
+/// `read_byte` - Whether the adapter supports SMBus Receive Byte
+fn probe_method(probe: Probe, address: u16, quick: bool, read_byte: bool) -> Option<Method> {
+    let use_read = match probe {
+        Probe::Auto => matches!(address, 0x30..=0x37 | 0x50..=0x5F),
+        Probe::QuickWrite => false,
+        Probe::ReadByte => true,
+    };
+    match (use_read, quick, read_byte) {
+        (true, _, true) => Some(Method::ReadByte),
+        (false, true, _) => Some(Method::QuickWrite),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_prefers_read_for_eeproms() {
+        for &address in &[0x30, 0x37, 0x50, 0x5F] {
+            assert_eq!(
+                probe_method(Probe::Auto, address, true, true),
+                Some(Method::ReadByte)
+            );
+        }
+        for &address in &[0x08, 0x2F, 0x38, 0x4F, 0x60, 0x77] {
+            assert_eq!(
+                probe_method(Probe::Auto, address, true, true),
+                Some(Method::QuickWrite)
+            );
+        }
+    }
+
+    #[test]
+    fn auto_skips_eeproms_without_read_byte() {
+        assert_eq!(probe_method(Probe::Auto, 0x50, true, false), None);
+        assert_eq!(
+            probe_method(Probe::Auto, 0x48, true, false),
+            Some(Method::QuickWrite)
+        );
+    }
+
+    #[test]
+    fn auto_skips_others_without_quick() {
+        assert_eq!(probe_method(Probe::Auto, 0x48, false, true), None);
+        assert_eq!(
+            probe_method(Probe::Auto, 0x50, false, true),
+            Some(Method::ReadByte)
+        );
+    }
+
+    #[test]
+    fn explicit_probe_applies_to_every_address() {
+        for &address in &[0x08, 0x50, 0x77] {
+            assert_eq!(
+                probe_method(Probe::QuickWrite, address, true, true),
+                Some(Method::QuickWrite)
+            );
+            assert_eq!(
+                probe_method(Probe::ReadByte, address, true, true),
+                Some(Method::ReadByte)
+            );
+        }
+    }
+}
@@ -0,0 +1,427 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Simulated I2C devices and buses
+//!
+//! A [`RegisterDevice`] can be used as a stream on its own:
+//!
+//! ```
+//! use i2c_rs::sim::{Access, RegisterDevice};
+//! use i2c_rs::{Command, Connection};
+//! use std::time::Duration;
+//!
+//! let device = RegisterDevice::new();
+//! device.set_access(0x00, Access::ReadOnly);
+//! device.set(0x00, 0xA5);
+//!
+//! let connection = Connection::new(Box::new(device.clone()));
+//! connection.write(Command { cmd: 0x01, data: vec![0x11, 0x22] }).unwrap();
+//! let data = connection.transfer(Command { cmd: 0x00, data: vec![] }, 3, Duration::from_millis(0));
+//! assert_eq!(data.unwrap(), vec![0xA5, 0x11, 0x22]);
+//! ```
+//!
+//! or, together with other devices, be attached to a [`VirtualBus`]:
+//!
+//! ```
+//! use i2c_rs::sim::{RegisterDevice, VirtualBus};
+//! use i2c_rs::{I2cError, I2cErrorKind};
+//!
+//! let bus = VirtualBus::new("/dev/i2c-sim0");
+//! bus.attach(0x48, RegisterDevice::new());
+//! bus.attach(0x49, RegisterDevice::new());
+//!
+//! let temp = bus.connection(0x48);
+//! temp.write_byte_data(0x01, 0x60).unwrap();
+//! assert_eq!(temp.read_byte_data(0x01).unwrap(), 0x60);
+//!
+//! let missing = bus.connection(0x50);
+//! assert_eq!(missing.read_byte_data(0x00).unwrap_err().kind(), I2cErrorKind::Nack);
+//! ```
+
+use crate::{Address, Connection, ErrorContext, I2cError};
+use hal_stream::Stream;
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// A simulated slave which can be attached to a [`VirtualBus`]
+///
+/// The bus calls it once per I2C message addressed to the slave.
+/// Returning an error makes the message fail as if the slave had NACKed
+/// it or the bus had failed in some other way.
+pub trait SimDevice: Send {
+    /// Handles a write message
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Handles a read message of `len` bytes
+    fn read(&mut self, len: usize) -> Result<Vec<u8>>;
+}
+
+/// How a register responds to the bus
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// Readable and writable
+    ReadWrite,
+    /// Writes are ignored
+    ReadOnly,
+    /// Reads return zero
+    WriteOnly,
+    /// Reading returns the value and then clears the register
+    ClearOnRead,
+}
+
+/// Hook run after the bus writes a register
+///
+/// It receives the register file, the register address and the value
+/// written, and may update any register, e.g. to clear a self-clearing
+/// reset bit or to latch a measurement.
+pub type WriteHook = Box<dyn FnMut(&mut Registers, u8, u8) + Send>;
+
+/// Contents of a simulated device's 256 eight-bit registers
+pub struct Registers {
+    values: [u8; 256],
+}
+
+impl Registers {
+    /// Value of a register
+    pub fn get(&self, reg: u8) -> u8 {
+        self.values[reg as usize]
+    }
+
+    /// Sets a register, regardless of its access mode
+    pub fn set(&mut self, reg: u8, value: u8) {
+        self.values[reg as usize] = value;
+    }
+}
+
+struct State {
+    registers: Registers,
+    access: [Access; 256],
+    hooks: Vec<Option<WriteHook>>,
+    pointer: u8,
+}
+
+/// A simulated register-based slave
+///
+/// The device has 256 eight-bit registers and a register pointer. The
+/// first byte of every write sets the pointer, any further bytes are
+/// written to consecutive registers starting there, and reads return
+/// consecutive registers starting at the pointer. The pointer
+/// auto-increments after each byte and wraps around from `0xFF` to `0x00`.
+///
+/// Clones share the same state, so a clone can be handed to a `Connection`
+/// while the test inspects and manipulates the registers through another.
+#[derive(Clone)]
+pub struct RegisterDevice {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for RegisterDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegisterDevice {
+    /// Creates a device with all registers zeroed and read-write
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                registers: Registers { values: [0; 256] },
+                access: [Access::ReadWrite; 256],
+                hooks: (0..256).map(|_| None).collect(),
+                pointer: 0,
+            })),
+        }
+    }
+
+    /// Value of a register, without side effects
+    pub fn get(&self, reg: u8) -> u8 {
+        self.lock().registers.get(reg)
+    }
+
+    /// Sets a register, regardless of its access mode and without running
+    /// its hook
+    pub fn set(&self, reg: u8, value: u8) {
+        self.lock().registers.set(reg, value)
+    }
+
+    /// Sets how a register responds to the bus
+    pub fn set_access(&self, reg: u8, access: Access) {
+        self.lock().access[reg as usize] = access;
+    }
+
+    /// Sets the hook run after the bus writes a register
+    ///
+    /// The hook also runs for writes to read-only registers, which are
+    /// otherwise ignored.
+    pub fn on_write<F>(&self, reg: u8, hook: F)
+    where
+        F: FnMut(&mut Registers, u8, u8) + Send + 'static,
+    {
+        self.lock().hooks[reg as usize] = Some(Box::new(hook));
+    }
+
+    /// Current register pointer
+    pub fn pointer(&self) -> u8 {
+        self.lock().pointer
+    }
+
+    /// Handles a write message from the bus
+    pub fn bus_write(&self, data: &[u8]) {
+        let mut state = self.lock();
+        let (pointer, data) = match data.split_first() {
+            Some((&pointer, data)) => (pointer, data),
+            None => return,
+        };
+        state.pointer = pointer;
+        for &value in data {
+            let reg = state.pointer;
+            state.write(reg, value);
+            state.pointer = reg.wrapping_add(1);
+        }
+    }
+
+    /// Handles a read message from the bus
+    pub fn bus_read(&self, len: usize) -> Vec<u8> {
+        let mut state = self.lock();
+        (0..len)
+            .map(|_| {
+                let reg = state.pointer;
+                state.pointer = reg.wrapping_add(1);
+                state.read(reg)
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn write(&mut self, reg: u8, value: u8) {
+        if self.access[reg as usize] != Access::ReadOnly {
+            self.registers.set(reg, value);
+        }
+        if let Some(hook) = self.hooks[reg as usize].as_mut() {
+            hook(&mut self.registers, reg, value);
+        }
+    }
+
+    fn read(&mut self, reg: u8) -> u8 {
+        match self.access[reg as usize] {
+            Access::ReadWrite | Access::ReadOnly => self.registers.get(reg),
+            Access::WriteOnly => 0,
+            Access::ClearOnRead => {
+                let value = self.registers.get(reg);
+                self.registers.set(reg, 0);
+                value
+            }
+        }
+    }
+}
+
+impl Stream for RegisterDevice {
+    type StreamError = std::io::Error;
+
+    fn write(&self, command: Vec<u8>) -> Result<()> {
+        self.bus_write(&command);
+        Ok(())
+    }
+
+    fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
+        self.write(command)
+    }
+
+    fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
+        self.bus_write(command);
+        Ok(self.bus_read(rx_len))
+    }
+
+    fn read_timeout(
+        &self,
+        command: &mut Vec<u8>,
+        rx_len: usize,
+        _timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.read(command, rx_len)
+    }
+
+    /// Writes `command` and reads `rx_len` bytes, ignoring `delay`
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        _delay: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.bus_write(&command);
+        Ok(self.bus_read(rx_len))
+    }
+}
+
+impl SimDevice for RegisterDevice {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.bus_write(data);
+        Ok(())
+    }
+
+    fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        Ok(self.bus_read(len))
+    }
+}
+
+/// An in-process I2C bus with simulated devices attached by address
+///
+/// Streams and connections created from the bus route each message to the
+/// device attached at their address. Messages to addresses without a device
+/// fail with [`I2cError::Nack`], like on real hardware. Clones share the same
+/// set of devices.
+#[derive(Clone)]
+pub struct VirtualBus {
+    inner: Arc<BusInner>,
+}
+
+struct BusInner {
+    path: String,
+    devices: Mutex<HashMap<Address, Box<dyn SimDevice>>>,
+}
+
+impl VirtualBus {
+    /// Creates a bus without devices
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Name of the bus, reported in errors in place of the path to
+    ///          an I2C adapter
+    pub fn new(path: &str) -> Self {
+        Self {
+            inner: Arc::new(BusInner {
+                path: path.to_string(),
+                devices: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Name of the bus
+    pub fn path(&self) -> &str {
+        &self.inner.path
+    }
+
+    /// Attaches a device, replacing any device already at `address`
+    ///
+    /// # Arguments
+    ///
+    /// `address` - Slave address, either a plain 7-bit address or an
+    ///             [`Address`]
+    /// `device` - Device to attach
+    pub fn attach(&self, address: impl Into<Address>, device: impl SimDevice + 'static) {
+        self.lock().insert(address.into(), Box::new(device));
+    }
+
+    /// Detaches the device at `address`, returning whether there was one
+    pub fn detach(&self, address: impl Into<Address>) -> bool {
+        self.lock().remove(&address.into()).is_some()
+    }
+
+    /// Creates a stream talking to `address`
+    pub fn stream(&self, address: impl Into<Address>) -> VirtualStream {
+        VirtualStream {
+            bus: self.clone(),
+            address: address.into(),
+        }
+    }
+
+    /// Creates a connection to `address`, the counterpart of
+    /// [`Connection::from_path`]
+    pub fn connection(&self, address: impl Into<Address>) -> Connection {
+        let address = address.into();
+        Connection::new(Box::new(self.stream(address))).located(self.path(), address)
+    }
+
+    /// Runs `f` against the device at `address`, with the bus held for the
+    /// whole time so that the messages `f` sends form one transaction
+    fn transaction<T, F>(&self, address: Address, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut dyn SimDevice) -> Result<T>,
+    {
+        let mut devices = self.lock();
+        let device = devices.get_mut(&address).ok_or_else(|| {
+            I2cError::Nack(ErrorContext {
+                path: Some(self.inner.path.clone()),
+                address: Some(address),
+                command: None,
+            })
+        })?;
+        f(device.as_mut())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Address, Box<dyn SimDevice>>> {
+        self.inner.devices.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A `Stream` talking to one address on a [`VirtualBus`]
+#[derive(Clone)]
+pub struct VirtualStream {
+    bus: VirtualBus,
+    address: Address,
+}
+
+impl Stream for VirtualStream {
+    type StreamError = std::io::Error;
+
+    fn write(&self, command: Vec<u8>) -> Result<()> {
+        self.bus.transaction(self.address, |d| d.write(&command))
+    }
+
+    fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
+        self.write(command)
+    }
+
+    /// Writes `command`, unless it is empty, and reads `rx_len` bytes in a
+    /// single transaction
+    fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
+        self.bus.transaction(self.address, |d| {
+            if !command.is_empty() {
+                d.write(command)?;
+            }
+            d.read(rx_len)
+        })
+    }
+
+    fn read_timeout(
+        &self,
+        command: &mut Vec<u8>,
+        rx_len: usize,
+        _timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.read(command, rx_len)
+    }
+
+    /// Writes `command` and reads `rx_len` bytes in separate transactions,
+    /// ignoring `delay`
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        _delay: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.write(command)?;
+        self.bus.transaction(self.address, |d| d.read(rx_len))
+    }
+}
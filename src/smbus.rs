@@ -0,0 +1,327 @@
+/*
+ * Copyright (C) 2022 CUAVA
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! SMBus transactions
+//!
+//! [`SmbusStream`] extends `Stream` with one method per SMBus transaction
+//! type. Every method has a default implementation which frames the
+//! transaction as raw I2C writes and combined reads through the underlying
+//! `Stream`, so any `Stream` (including mocks) can be used for SMBus devices.
+//! [`I2CStream`](crate::I2CStream) overrides them with the kernel's native
+//! SMBus transactions.
+
+use hal_stream::Stream;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Deref;
+use std::time::Duration;
+
+/// Largest data payload of a single SMBus block transaction
+pub const BLOCK_MAX: usize = 32;
+
+/// A `Stream` which can perform SMBus transactions
+///
+/// Words are transferred least significant byte first, as specified by
+/// SMBus. Block transfers carry a byte count ahead of the data and are
+/// limited to [`BLOCK_MAX`] data bytes.
+pub trait SmbusStream: Stream<StreamError = Error> {
+    /// Quick Command: sends just the R/W bit
+    ///
+    /// # Arguments
+    ///
+    /// `read` - Value of the R/W bit
+    fn quick_command(&self, read: bool) -> Result<()> {
+        if read {
+            self.read(&mut Vec::new(), 0).map(drop)
+        } else {
+            self.write(Vec::new())
+        }
+    }
+
+    /// Receive Byte: reads a single byte without a command
+    fn receive_byte(&self) -> Result<u8> {
+        let data = self.read(&mut Vec::new(), 1)?;
+        Ok(exact::<1>(&data)?[0])
+    }
+
+    /// Send Byte: writes a single byte without a command
+    ///
+    /// # Arguments
+    ///
+    /// `value` - Byte to send
+    fn send_byte(&self, value: u8) -> Result<()> {
+        self.write(vec![value])
+    }
+
+    /// Read Byte: reads a byte from a command/register
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    fn read_byte_data(&self, cmd: u8) -> Result<u8> {
+        let data = self.read(&mut vec![cmd], 1)?;
+        Ok(exact::<1>(&data)?[0])
+    }
+
+    /// Write Byte: writes a byte to a command/register
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Byte to write
+    fn write_byte_data(&self, cmd: u8, value: u8) -> Result<()> {
+        self.write(vec![cmd, value])
+    }
+
+    /// Read Word: reads a little-endian word from a command/register
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    fn read_word_data(&self, cmd: u8) -> Result<u16> {
+        let data = self.read(&mut vec![cmd], 2)?;
+        Ok(u16::from_le_bytes(exact::<2>(&data)?))
+    }
+
+    /// Write Word: writes a little-endian word to a command/register
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Word to write
+    fn write_word_data(&self, cmd: u8, value: u16) -> Result<()> {
+        let [lo, hi] = value.to_le_bytes();
+        self.write(vec![cmd, lo, hi])
+    }
+
+    /// Process Call: writes a word and reads a word back
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `value` - Word to write
+    fn process_call(&self, cmd: u8, value: u16) -> Result<u16> {
+        let [lo, hi] = value.to_le_bytes();
+        let data = self.read(&mut vec![cmd, lo, hi], 2)?;
+        Ok(u16::from_le_bytes(exact::<2>(&data)?))
+    }
+
+    /// Block Read: reads a length-prefixed block from a command/register
+    ///
+    /// When emulated over plain I2C the maximum block size is read and any
+    /// bytes past the received byte count are discarded.
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    fn read_block_data(&self, cmd: u8) -> Result<Vec<u8>> {
+        let data = self.read(&mut vec![cmd], BLOCK_MAX + 1)?;
+        parse_block(data)
+    }
+
+    /// Block Write: writes a length-prefixed block to a command/register
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `data` - Up to [`BLOCK_MAX`] bytes to write
+    fn write_block_data(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        let mut buf = block_header(cmd, data)?;
+        buf.extend_from_slice(data);
+        self.write(buf)
+    }
+
+    /// Block Write-Block Read Process Call: writes a block and reads a
+    /// block back
+    ///
+    /// # Arguments
+    ///
+    /// `cmd` - Command code
+    /// `data` - Up to [`BLOCK_MAX`] bytes to write
+    fn block_process_call(&self, cmd: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = block_header(cmd, data)?;
+        buf.extend_from_slice(data);
+        let data = self.read(&mut buf, BLOCK_MAX + 1)?;
+        parse_block(data)
+    }
+
+    /// Runs `f` with the bus locked, so that the transactions it makes
+    /// can't be interleaved with other users of the bus
+    ///
+    /// Streams without a bus lock just run `f`.
+    ///
+    /// # Arguments
+    ///
+    /// `f` - Sequence of transactions to run
+    fn exclusive(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        f()
+    }
+}
+
+/// Adapts a (pointer to a) plain `Stream` to [`SmbusStream`] using the
+/// emulated SMBus transactions
+pub(crate) struct Emulated<T>(pub(crate) T);
+
+impl<T, S> Stream for Emulated<T>
+where
+    T: Deref<Target = S>,
+    S: Stream<StreamError = Error> + ?Sized,
+{
+    type StreamError = Error;
+
+    fn write(&self, command: Vec<u8>) -> Result<()> {
+        self.0.write(command)
+    }
+
+    fn write_bytes(&self, command: Vec<u8>) -> Result<()> {
+        self.0.write_bytes(command)
+    }
+
+    fn read(&self, command: &mut Vec<u8>, rx_len: usize) -> Result<Vec<u8>> {
+        self.0.read(command, rx_len)
+    }
+
+    fn read_timeout(
+        &self,
+        command: &mut Vec<u8>,
+        rx_len: usize,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        self.0.read_timeout(command, rx_len, timeout)
+    }
+
+    fn transfer(
+        &self,
+        command: Vec<u8>,
+        rx_len: usize,
+        delay: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        self.0.transfer(command, rx_len, delay)
+    }
+}
+
+impl<T, S> SmbusStream for Emulated<T>
+where
+    T: Deref<Target = S>,
+    S: Stream<StreamError = Error> + ?Sized,
+{
+}
+
+/// Command code and byte count which start a block write
+fn block_header(cmd: u8, data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() > BLOCK_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "SMBus block of {} bytes exceeds {} bytes",
+                data.len(),
+                BLOCK_MAX
+            ),
+        ));
+    }
+    Ok(vec![cmd, data.len() as u8])
+}
+
+/// Extracts the data of a block read from the byte count and data bytes
+pub(crate) fn parse_block(mut data: Vec<u8>) -> Result<Vec<u8>> {
+    let count = match data.first() {
+        Some(&count) => count as usize,
+        None => return Err(short(0, 1)),
+    };
+    if count > BLOCK_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("SMBus block count {} exceeds {} bytes", count, BLOCK_MAX),
+        ));
+    }
+    if data.len() <= count {
+        return Err(short(data.len(), count + 1));
+    }
+    data.truncate(count + 1);
+    data.remove(0);
+    Ok(data)
+}
+
+/// Checks that a response has exactly the expected length
+fn exact<const N: usize>(data: &[u8]) -> Result<[u8; N]> {
+    use std::convert::TryInto;
+    data.try_into().map_err(|_| short(data.len(), N))
+}
+
+fn short(got: usize, expected: usize) -> Error {
+    Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("expected {} bytes from slave, got {}", expected, got),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockStream;
+
+    #[test]
+    fn write_word_data_is_little_endian() {
+        let mock = MockStream::new();
+        mock.expect_write(vec![0x10, 0x34, 0x12], Ok(()));
+        Emulated(&mock).write_word_data(0x10, 0x1234).unwrap();
+        mock.verify();
+    }
+
+    #[test]
+    fn read_block_data_drops_padding() {
+        let mut response = vec![3, 0xAA, 0xBB, 0xCC];
+        response.resize(BLOCK_MAX + 1, 0xFF);
+        let mock = MockStream::new();
+        mock.expect_read(vec![0x20], BLOCK_MAX + 1, Ok(response));
+        let data = Emulated(&mock).read_block_data(0x20).unwrap();
+        assert_eq!(data, [0xAA, 0xBB, 0xCC]);
+        mock.verify();
+    }
+
+    #[test]
+    fn parse_block_count_too_large() {
+        let mut data = vec![BLOCK_MAX as u8 + 1];
+        data.resize(BLOCK_MAX + 2, 0);
+        let err = parse_block(data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parse_block_short_data() {
+        let err = parse_block(vec![4, 0x01, 0x02]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let err = parse_block(Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(parse_block(vec![0]).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn block_header_length_limit() {
+        assert_eq!(block_header(0x30, &[0; BLOCK_MAX]).unwrap(), [0x30, 32]);
+        let err = block_header(0x30, &[0; BLOCK_MAX + 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn oversized_block_write_is_not_sent() {
+        let mock = MockStream::new();
+        let err = Emulated(&mock)
+            .write_block_data(0x30, &[0; BLOCK_MAX + 1])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        mock.verify();
+    }
+}